@@ -0,0 +1,371 @@
+use crate::errors::{Errors, Result};
+use log::{debug, error};
+use parking_lot::Mutex;
+use std::{
+    io::{self, Read, Write},
+    marker::PhantomData,
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    thread,
+};
+#[cfg(unix)]
+use std::{
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+};
+
+use super::mspc_channel::{InternalMessage, Sender};
+use super::wal::{length, put_bytes, Persist, Reader};
+
+/// the largest message frame a server accepts
+pub(crate) const MAX_FRAME_SIZE: usize = 64 << 20;
+/// a response is a status code and a message id
+const RESPONSE_SIZE: usize = 9;
+/// the status code of a sent message
+const STATUS_OK: u8 = 0;
+
+/// `Listener` is a bound tcp or unix socket
+#[derive(Debug)]
+enum Listener {
+    /// a loopback tcp socket
+    Tcp(TcpListener),
+    /// a unix domain socket
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+/// `Stream` is a connection to a bridge server
+#[derive(Debug)]
+enum Stream {
+    /// a loopback tcp connection
+    Tcp(TcpStream),
+    /// a unix domain socket connection
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Stream::Tcp(ref mut stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+/// `BridgeServer` feeds the messages of other processes into a channel.
+/// every connection is served by its own thread, which sends each message
+/// before it answers, so a full bounded channel holds the client back
+#[derive(Debug)]
+pub struct BridgeServer<T> {
+    /// the bound socket
+    listener: Listener,
+    /// the sender of the channel
+    sender: Sender<T>,
+}
+
+impl<T> BridgeServer<T>
+where
+    T: Persist + Send + 'static,
+{
+    /// `bind_tcp` binds a loopback tcp address, the bridge has no
+    /// authentication, so other addresses are refused
+    ///
+    /// # Errors
+    ///
+    /// it fails when the address is not a loopback one or can not be bound
+    #[inline]
+    pub fn bind_tcp<A: ToSocketAddrs>(addr: A, sender: Sender<T>) -> io::Result<BridgeServer<T>> {
+        let listener = TcpListener::bind(addr)?;
+        if !listener.local_addr()?.ip().is_loopback() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "a bridge only listens on a loopback address",
+            ));
+        }
+        Ok(BridgeServer {
+            listener: Listener::Tcp(listener),
+            sender,
+        })
+    }
+
+    /// `bind_unix` binds a unix domain socket at `path`
+    ///
+    /// # Errors
+    ///
+    /// it fails when the socket can not be bound
+    #[cfg(unix)]
+    #[inline]
+    pub fn bind_unix<P: AsRef<Path>>(path: P, sender: Sender<T>) -> io::Result<BridgeServer<T>> {
+        Ok(BridgeServer {
+            listener: Listener::Unix(UnixListener::bind(path)?),
+            sender,
+        })
+    }
+
+    /// `local_addr` is the bound tcp address, `None` for a unix socket
+    #[inline]
+    #[must_use]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self.listener {
+            Listener::Tcp(ref listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None,
+        }
+    }
+
+    /// `serve` accepts connections until accepting fails
+    ///
+    /// # Errors
+    ///
+    /// it returns the error of the failed accept
+    #[inline]
+    pub fn serve(&self) -> io::Result<()> {
+        loop {
+            let stream = match self.listener {
+                Listener::Tcp(ref listener) => Stream::Tcp(listener.accept()?.0),
+                #[cfg(unix)]
+                Listener::Unix(ref listener) => Stream::Unix(listener.accept()?.0),
+            };
+            let sender = self.sender.clone();
+            let _handler = thread::spawn(move || {
+                if let Err(err) = serve_connection(stream, &sender) {
+                    error!(error:% = err; "bridge connection failed");
+                }
+            });
+        }
+    }
+}
+
+/// answer the messages of one connection until the client hangs up
+fn serve_connection<T: Persist>(mut stream: Stream, sender: &Sender<T>) -> io::Result<()> {
+    loop {
+        let mut len = [0_u8; 4];
+        match stream.read_exact(&mut len) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        let len = usize::try_from(u32::from_le_bytes(len)).unwrap_or(usize::MAX);
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the message frame is too large",
+            ));
+        }
+        let mut body = vec![0_u8; len];
+        stream.read_exact(&mut body)?;
+        let result = decode_message(&body)
+            .ok_or(Errors::MessageContentError)
+            .and_then(|message| sender.send(message));
+        debug!(result:? = result; "bridge message handled");
+        let mut response = Vec::with_capacity(RESPONSE_SIZE);
+        match result {
+            Ok(id) => {
+                response.push(STATUS_OK);
+                response.extend(id.to_le_bytes());
+            }
+            Err(err) => {
+                response.push(error_code(err));
+                response.extend(0_u64.to_le_bytes());
+            }
+        }
+        stream.write_all(&response)?;
+    }
+}
+
+/// encode the keys and data of a message into a frame
+fn encode_message<T: Persist>(message: &InternalMessage<T>) -> io::Result<Vec<u8>> {
+    let body = encode_body(message)?;
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the message frame is too large",
+        ));
+    }
+    let mut frame = length(body.len())?.to_le_bytes().to_vec();
+    frame.extend(body);
+    Ok(frame)
+}
+
+/// encode the keys and data of a message
+pub(crate) fn encode_body<T: Persist>(message: &InternalMessage<T>) -> io::Result<Vec<u8>> {
+    let keys = message.key_names();
+    let mut body = Vec::new();
+    body.extend(length(keys.len())?.to_le_bytes());
+    for key in keys {
+        put_bytes(&mut body, key.as_bytes())?;
+    }
+    put_bytes(&mut body, &message.data.to_bytes())?;
+    Ok(body)
+}
+
+/// decode the body of a frame into a message
+pub(crate) fn decode_message<T: Persist>(body: &[u8]) -> Option<InternalMessage<T>> {
+    let mut reader = Reader(body);
+    let key_count = reader.u32()?;
+    let keys = (0..key_count)
+        .map(|_| reader.string())
+        .collect::<Option<Vec<String>>>()?;
+    let data = T::from_bytes(reader.bytes()?)?;
+    reader
+        .0
+        .is_empty()
+        .then(|| InternalMessage::new(keys, data))
+}
+
+/// the status code of an error
+fn error_code(err: Errors) -> u8 {
+    match err {
+        Errors::KeyDuplicate => 1,
+        Errors::MessageContentError => 2,
+        Errors::TypeConversionError => 3,
+        Errors::ExceedCapacity => 4,
+        Errors::WriteAheadLogError => 5,
+        Errors::ConnectionError => 6,
+        Errors::Closed => 7,
+        Errors::Paused => 8,
+        Errors::Empty => 9,
+    }
+}
+
+/// the error of a status code
+fn from_error_code(code: u8) -> Errors {
+    match code {
+        1 => Errors::KeyDuplicate,
+        2 => Errors::MessageContentError,
+        3 => Errors::TypeConversionError,
+        4 => Errors::ExceedCapacity,
+        5 => Errors::WriteAheadLogError,
+        7 => Errors::Closed,
+        8 => Errors::Paused,
+        9 => Errors::Empty,
+        _ => Errors::ConnectionError,
+    }
+}
+
+/// `BridgeClient` sends messages to a `BridgeServer` in another process
+#[derive(Debug)]
+pub struct BridgeClient<T> {
+    /// the connection, one message is on the way at a time
+    stream: Mutex<Stream>,
+    /// the type of the message data
+    data: PhantomData<fn(T)>,
+}
+
+impl<T: Persist> BridgeClient<T> {
+    /// `connect_tcp` connects to a server listening on a tcp address
+    ///
+    /// # Errors
+    ///
+    /// it fails when the server can not be reached
+    #[inline]
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> io::Result<BridgeClient<T>> {
+        let stream = TcpStream::connect(addr)?;
+        // a message is small and answered right away
+        stream.set_nodelay(true)?;
+        Ok(BridgeClient {
+            stream: Mutex::new(Stream::Tcp(stream)),
+            data: PhantomData,
+        })
+    }
+
+    /// `connect_unix` connects to a server listening on a unix socket
+    ///
+    /// # Errors
+    ///
+    /// it fails when the server can not be reached
+    #[cfg(unix)]
+    #[inline]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> io::Result<BridgeClient<T>> {
+        Ok(BridgeClient {
+            stream: Mutex::new(Stream::Unix(UnixStream::connect(path)?)),
+            data: PhantomData,
+        })
+    }
+
+    /// `send` sends a message like `Sender::send` and returns its id. it
+    /// waits while the bounded channel of the server is full
+    ///
+    /// # Errors
+    ///
+    /// it returns the error of the server, or `ConnectionError` when the
+    /// connection is broken
+    #[inline]
+    #[allow(clippy::needless_pass_by_value)] // the message is sent away like `Sender::send`
+    pub fn send(&self, message: InternalMessage<T>) -> Result<u64> {
+        let frame = encode_message(&message).or(Err(Errors::MessageContentError))?;
+        let mut stream = self.stream.lock();
+        let mut response = [0_u8; RESPONSE_SIZE];
+        stream
+            .write_all(&frame)
+            .and_then(|()| stream.read_exact(&mut response))
+            .or(Err(Errors::ConnectionError))?;
+        let (status, id) = response.split_at(1);
+        let id = u64::from_le_bytes(id.try_into().or(Err(Errors::ConnectionError))?);
+        match status.first().copied() {
+            Some(STATUS_OK) => Ok(id),
+            Some(code) => Err(from_error_code(code)),
+            None => Err(Errors::ConnectionError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_bridge {
+    use super::{decode_message, encode_message, error_code, from_error_code};
+    use crate::{channel::mspc_channel::InternalMessage, errors::Errors};
+
+    #[test]
+    fn test_message_frame() {
+        let keys = vec![String::from("a"), String::from("b")];
+        let frame = encode_message(&InternalMessage::new(keys, 7_u64)).unwrap_or_default();
+        let (len, body) = frame.split_at(4);
+        assert_eq!(len, u32::try_from(body.len()).unwrap_or(0).to_le_bytes());
+        let message = decode_message::<u64>(body).map(|message| message.to_detached());
+        assert_eq!(
+            message.map(|message| (message.keys, message.data)),
+            Some((vec![String::from("a"), String::from("b")], 7))
+        );
+        // a torn frame or trailing garbage is not a message
+        assert!(decode_message::<u64>(body.get(1..).unwrap_or_default()).is_none());
+        let mut longer = body.to_vec();
+        longer.push(0);
+        assert!(decode_message::<u64>(&longer).is_none());
+    }
+
+    #[test]
+    fn test_error_codes() {
+        for err in [
+            Errors::KeyDuplicate,
+            Errors::MessageContentError,
+            Errors::TypeConversionError,
+            Errors::ExceedCapacity,
+            Errors::WriteAheadLogError,
+            Errors::ConnectionError,
+            Errors::Closed,
+            Errors::Paused,
+            Errors::Empty,
+        ] {
+            assert_eq!(from_error_code(error_code(err)), err);
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use std::time::Duration;
+
+/// how many keys a channel tracks for the hot key report
+pub(crate) const HOT_KEY_CAPACITY: usize = 128;
+
+/// `HotKey` tells how much a key blocks other messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HotKey {
+    /// the key
+    pub key: String,
+    /// how many messages were blocked by the key, it may be
+    /// overestimated by at most `count_error`
+    pub blocked_count: u64,
+    /// the largest possible overestimation of `blocked_count`
+    pub count_error: u64,
+    /// total time messages spent blocked by the key since it is tracked
+    pub blocked_time: Duration,
+}
+
+/// `HotKeySketch` finds the keys blocking messages most often with the
+/// space-saving algorithm, so its memory is bounded by `capacity` however
+/// many distinct keys the channel sees
+#[derive(Debug)]
+pub(crate) struct HotKeySketch {
+    /// the tracked keys
+    entries: Vec<HotKey>,
+    /// the max number of tracked keys
+    capacity: usize,
+}
+
+impl HotKeySketch {
+    /// create a sketch tracking at most `capacity` keys
+    pub(crate) fn new(capacity: usize) -> HotKeySketch {
+        HotKeySketch {
+            entries: Vec::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// a message is blocked by `key`
+    pub(crate) fn record_blocked(&mut self, key: &str) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.key == key) {
+            entry.blocked_count = entry.blocked_count.saturating_add(1);
+            return;
+        }
+        if self.entries.len() < self.capacity {
+            self.entries.push(HotKey {
+                key: String::from(key),
+                blocked_count: 1,
+                count_error: 0,
+                blocked_time: Duration::ZERO,
+            });
+            return;
+        }
+        // replace the least blocking key, the new key may have been
+        // counted as often as it before it was evicted
+        if let Some(entry) = self
+            .entries
+            .iter_mut()
+            .min_by_key(|entry| entry.blocked_count)
+        {
+            *entry = HotKey {
+                key: String::from(key),
+                blocked_count: entry.blocked_count.saturating_add(1),
+                count_error: entry.blocked_count,
+                blocked_time: Duration::ZERO,
+            };
+        }
+    }
+
+    /// a message was blocked by `key` for `duration`
+    pub(crate) fn record_blocked_time(&mut self, key: &str, duration: Duration) {
+        if let Some(entry) = self.entries.iter_mut().find(|entry| entry.key == key) {
+            entry.blocked_time = entry.blocked_time.saturating_add(duration);
+        }
+    }
+
+    /// get the `n` keys blocking messages most often
+    pub(crate) fn top(&self, n: usize) -> Vec<HotKey> {
+        let mut entries = self.entries.clone();
+        entries.sort_by(|left, right| {
+            right
+                .blocked_count
+                .cmp(&left.blocked_count)
+                .then_with(|| left.key.cmp(&right.key))
+        });
+        entries.truncate(n);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod test_contention {
+    use super::HotKeySketch;
+    use std::time::Duration;
+    #[test]
+    fn test_sketch_keeps_heavy_hitters() {
+        let mut sketch = HotKeySketch::new(4);
+        for _ in 0..10 {
+            sketch.record_blocked("hot");
+        }
+        for i in 0..20 {
+            sketch.record_blocked(&std::format!("cold{i}"));
+        }
+        sketch.record_blocked_time("hot", Duration::from_millis(3));
+        let top = sketch.top(1);
+        let hot: Vec<_> = top
+            .iter()
+            .map(|hot| {
+                (
+                    hot.key.as_str(),
+                    hot.blocked_count,
+                    hot.count_error,
+                    hot.blocked_time,
+                )
+            })
+            .collect();
+        assert_eq!(hot, vec![("hot", 10, 0, Duration::from_millis(3))]);
+        // memory stays bounded by the capacity
+        assert_eq!(sketch.top(100).len(), 4);
+    }
+}
@@ -0,0 +1,135 @@
+use super::mspc_channel::InternalMessage;
+#[cfg(feature = "serde")]
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
+use std::time::SystemTime;
+
+/// `MessageMetadata` is what a channel recorded about a message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct MessageMetadata {
+    /// when the channel took the message, `None` if it was never sent.
+    /// a recovered durable channel takes its messages again
+    pub sent_at: Option<SystemTime>,
+}
+
+/// `DetachedMessage` is a message without the channel it belongs to, so
+/// it can be stored or shipped and turned into a sendable message again
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub struct DetachedMessage<T> {
+    /// the message keys
+    pub keys: Vec<String>,
+    /// the message data
+    pub data: T,
+    /// the id given by the channel the message was sent to, 0 if it was
+    /// never sent. ids belong to a channel, so a reconstructed message
+    /// gets a new one when it is sent
+    pub id: u64,
+    /// what the channel recorded about the message, a reconstructed
+    /// message gets new metadata when it is sent
+    pub metadata: MessageMetadata,
+}
+
+impl<T> DetachedMessage<T> {
+    /// create a detached message which was never sent
+    #[inline]
+    pub fn new(keys: Vec<String>, data: T) -> DetachedMessage<T> {
+        DetachedMessage {
+            keys,
+            data,
+            id: 0,
+            metadata: MessageMetadata::default(),
+        }
+    }
+
+    /// reconstruct a message which can be sent to any channel
+    #[inline]
+    pub fn into_message(self) -> InternalMessage<T> {
+        InternalMessage::new(self.keys, self.data)
+    }
+}
+
+impl<T> From<DetachedMessage<T>> for InternalMessage<T> {
+    #[inline]
+    fn from(message: DetachedMessage<T>) -> Self {
+        message.into_message()
+    }
+}
+
+/// a message is serialized as its `DetachedMessage`
+#[cfg(feature = "serde")]
+impl<T: Serialize> Serialize for InternalMessage<T> {
+    #[inline]
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("DetachedMessage", 4)?;
+        state.serialize_field("keys", &self.key_names())?;
+        state.serialize_field("data", &self.data)?;
+        state.serialize_field("id", &self.id())?;
+        state.serialize_field("metadata", &self.metadata())?;
+        state.end()
+    }
+}
+
+/// a message is deserialized from its `DetachedMessage`, it is not
+/// sent yet, so its id is 0 and it has no metadata
+#[cfg(feature = "serde")]
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for InternalMessage<T> {
+    #[inline]
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        DetachedMessage::deserialize(deserializer).map(DetachedMessage::into_message)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test_detached {
+    use super::{DetachedMessage, MessageMetadata};
+    use crate::channel::mspc_channel::{InternalMessage, MspcChannel};
+
+    #[test]
+    fn test_detach_and_reconstruct() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        let keys = vec![String::from("a"), String::from("b")];
+        let id = sender.send(InternalMessage::new(keys.clone(), 7)).unwrap();
+        let message = reciever.recv().unwrap();
+        let detached = message.to_detached();
+        assert_eq!((&detached.keys, detached.data, detached.id), (&keys, 7, id));
+        assert!(detached.metadata.sent_at.is_some());
+        assert_eq!(
+            DetachedMessage::new(keys, 7).metadata,
+            MessageMetadata::default()
+        );
+        // the recieved message still holds its keys
+        assert_eq!(sender.stats().active_keys, 2);
+        drop(message);
+        let resent = sender.send(detached.into()).unwrap();
+        assert_ne!(resent, id);
+        assert_eq!(reciever.recv().unwrap().data, 7);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_round_trip() {
+        let (sender, reciever) = MspcChannel::<String>::channel(-1);
+        let keys = vec![String::from("a")];
+        let id = sender
+            .send(InternalMessage::new(keys.clone(), String::from("data")))
+            .unwrap();
+        let message = reciever.recv().unwrap();
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.starts_with(&format!(
+            r#"{{"keys":["a"],"data":"data","id":{id},"metadata":{{"sent_at":{{"#
+        )));
+        let detached: DetachedMessage<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(detached.id, id);
+        assert_eq!(detached.metadata, message.to_detached().metadata);
+        let restored: InternalMessage<String> = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.id(), 0);
+        assert_eq!(
+            restored.to_detached(),
+            DetachedMessage::new(keys, String::from("data"))
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+/// `Slot` is a box of an `Inbox`
+#[derive(Debug)]
+struct Slot<E> {
+    /// the items with their `seq`, in the order they came
+    items: Mutex<Vec<(u64, E)>>,
+    /// whether `items` holds any, so draining skips the empty boxes
+    filled: AtomicBool,
+}
+
+/// `Inbox` holds the sent messages until the reciever moves them into the
+/// queue, so a send does not lock the `state` of the channel.
+///
+/// it has one box per key shard and a sender fills the box of a shard it
+/// holds, so senders of unrelated keys do not wait for each other here.
+/// the boxes are leaf locks
+#[derive(Debug)]
+pub(crate) struct Inbox<E> {
+    /// the boxes
+    boxes: Box<[Slot<E>]>,
+    /// the last `seq` given, `seq` follows the order the items are pushed
+    pushed: AtomicU64,
+    /// how many items wait in the boxes
+    len: AtomicUsize,
+}
+
+impl<E> Inbox<E> {
+    /// create an inbox with `boxes` boxes, at least one
+    pub(crate) fn new(boxes: usize) -> Inbox<E> {
+        Inbox {
+            boxes: (0..boxes.max(1))
+                .map(|_| Slot {
+                    items: Mutex::new(Vec::new()),
+                    filled: AtomicBool::new(false),
+                })
+                .collect(),
+            pushed: AtomicU64::new(0),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// push `items` to the box `index`, in order
+    pub(crate) fn push<I>(&self, index: usize, items: I)
+    where
+        I: IntoIterator<Item = E>,
+    {
+        let len = self.boxes.len();
+        let slot = self
+            .boxes
+            .get(index.checked_rem(len).unwrap_or(0))
+            .or_else(|| self.boxes.first());
+        if let Some(slot) = slot {
+            let mut slot_items = slot.items.lock();
+            // `seq` is given under the box lock, so a box stays sorted
+            for item in items {
+                let seq = self.pushed.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+                slot_items.push((seq, item));
+                slot.filled.store(true, Ordering::SeqCst);
+                let _ = self.len.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// whether no item waits
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len.load(Ordering::SeqCst) == 0
+    }
+
+    /// take every waiting item out, in the order they were pushed
+    pub(crate) fn drain(&self) -> Vec<(u64, E)> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+        let mut items = Vec::new();
+        for slot in self
+            .boxes
+            .iter()
+            .filter(|slot| slot.filled.load(Ordering::SeqCst))
+        {
+            let mut slot_items = slot.items.lock();
+            items.append(&mut slot_items);
+            slot.filled.store(false, Ordering::SeqCst);
+        }
+        let _ = self.len.fetch_sub(items.len(), Ordering::SeqCst);
+        items.sort_unstable_by_key(|item| item.0);
+        items
+    }
+}
+
+#[cfg(test)]
+mod test_inbox {
+    use super::Inbox;
+
+    #[test]
+    fn test_drain_in_push_order() {
+        let inbox = Inbox::new(4);
+        inbox.push(3, ["a", "b"]);
+        inbox.push(1, ["c"]);
+        inbox.push(7, ["d"]);
+        assert!(!inbox.is_empty());
+        let items: Vec<&str> = inbox.drain().into_iter().map(|item| item.1).collect();
+        assert_eq!(items, vec!["a", "b", "c", "d"]);
+        assert!(inbox.is_empty());
+        assert!(inbox.drain().is_empty());
+    }
+}
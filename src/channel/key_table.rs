@@ -0,0 +1,215 @@
+use parking_lot::{Mutex, MutexGuard};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+};
+
+/// how many shards a channel splits its keys into by default
+pub(crate) const DEFAULT_SHARDS: usize = 16;
+
+/// `KeyShard` holds the state of the keys hashed to one shard
+#[derive(Debug, Default)]
+pub(crate) struct KeyShard {
+    /// key -> [`message_id0`,`message_id1`,...] queued in send order
+    pub(crate) waiting: HashMap<String, VecDeque<u64>>,
+    /// active key -> id of the recieved message holding it
+    pub(crate) active: HashMap<String, u64>,
+}
+
+/// `KeyTable` splits the key state into shards by key hash, so messages
+/// with unrelated keys do not wait for each other's keys.
+///
+/// shards are always locked in ascending order, and before the `state`
+/// of the channel, so two messages sharing several shards never wait
+/// for each other in a cycle
+#[derive(Debug)]
+pub(crate) struct KeyTable {
+    /// the shards, a key always lives in the same one
+    shards: Box<[Mutex<KeyShard>]>,
+}
+
+impl KeyTable {
+    /// create a table with `shards` shards, at least one
+    pub(crate) fn new(shards: usize) -> KeyTable {
+        KeyTable {
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(KeyShard::default()))
+                .collect(),
+        }
+    }
+
+    /// the shard of `key`, the same in every run
+    fn shard_of(&self, key: &str) -> usize {
+        // `DefaultHasher::new` has fixed keys, unlike `RandomState`
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let len = u64::try_from(self.shards.len()).unwrap_or(u64::MAX);
+        usize::try_from(hasher.finish().checked_rem(len).unwrap_or(0)).unwrap_or(0)
+    }
+
+    /// the shards of `keys` in the order they are locked
+    pub(crate) fn shards_of<'k, I>(&self, keys: I) -> Vec<usize>
+    where
+        I: IntoIterator<Item = &'k str>,
+    {
+        let mut shards: Vec<usize> = keys.into_iter().map(|key| self.shard_of(key)).collect();
+        shards.sort_unstable();
+        shards.dedup();
+        shards
+    }
+
+    /// lock the shards of `keys`
+    pub(crate) fn lock<'k, I>(&self, keys: I) -> KeyGuards<'_>
+    where
+        I: IntoIterator<Item = &'k str>,
+    {
+        self.lock_shards(&self.shards_of(keys))
+    }
+
+    /// lock every shard, for work which may touch any key
+    pub(crate) fn lock_all(&self) -> KeyGuards<'_> {
+        self.lock_shards(&(0..self.shards.len()).collect::<Vec<_>>())
+    }
+
+    /// lock `shards`, which are sorted and distinct
+    pub(crate) fn lock_shards(&self, shards: &[usize]) -> KeyGuards<'_> {
+        debug_assert!(shards.windows(2).all(|pair| pair.first() < pair.get(1)));
+        KeyGuards {
+            table: self,
+            guards: shards
+                .iter()
+                .filter_map(|index| Some((*index, self.shards.get(*index)?.lock())))
+                .collect(),
+        }
+    }
+
+    /// lock `shards` only if none of them is locked by someone else. it
+    /// never waits, so it may be called with the `state` of the channel
+    /// locked
+    pub(crate) fn try_lock_shards(&self, shards: &[usize]) -> Option<KeyGuards<'_>> {
+        let guards = shards
+            .iter()
+            .map(|index| Some((*index, self.shards.get(*index)?.try_lock()?)))
+            .collect::<Option<Vec<_>>>()?;
+        Some(KeyGuards {
+            table: self,
+            guards,
+        })
+    }
+}
+
+/// `KeyGuards` holds the locked shards of a `KeyTable`
+#[derive(Debug)]
+pub(crate) struct KeyGuards<'a> {
+    /// the table the shards belong to
+    table: &'a KeyTable,
+    /// shard index -> its guard, sorted by index
+    guards: Vec<(usize, MutexGuard<'a, KeyShard>)>,
+}
+
+impl KeyGuards<'_> {
+    /// the shard of `key`, which must be locked
+    pub(crate) fn shard(&self, key: &str) -> &KeyShard {
+        let index = self.table.shard_of(key);
+        self.guards
+            .binary_search_by_key(&index, |pair| pair.0)
+            .ok()
+            .and_then(|position| self.guards.get(position))
+            .map_or_else(
+                || panic!("shard of key {key} is not locked"),
+                |pair| &*pair.1,
+            )
+    }
+
+    /// the shard of `key`, which must be locked
+    pub(crate) fn shard_mut(&mut self, key: &str) -> &mut KeyShard {
+        let index = self.table.shard_of(key);
+        self.guards
+            .binary_search_by_key(&index, |pair| pair.0)
+            .ok()
+            .and_then(|position| self.guards.get_mut(position))
+            .map_or_else(
+                || panic!("shard of key {key} is not locked"),
+                |pair| &mut *pair.1,
+            )
+    }
+
+    /// the index of the first locked shard
+    pub(crate) fn first_shard(&self) -> Option<usize> {
+        self.guards.first().map(|pair| pair.0)
+    }
+
+    /// the locked shards
+    pub(crate) fn shards(&self) -> impl Iterator<Item = &KeyShard> {
+        self.guards.iter().map(|pair| &*pair.1)
+    }
+
+    /// forget the queued messages of the keys in the locked shards
+    pub(crate) fn clear_waiting(&mut self) {
+        for pair in &mut self.guards {
+            pair.1.waiting.clear();
+        }
+    }
+
+    /// whether a recieved message holds `key`
+    pub(crate) fn is_active(&self, key: &str) -> bool {
+        self.shard(key).active.contains_key(key)
+    }
+
+    /// the queued messages waiting for `key`
+    pub(crate) fn waiting(&self, key: &str) -> Option<&VecDeque<u64>> {
+        self.shard(key).waiting.get(key)
+    }
+}
+
+#[cfg(test)]
+mod test_key_table {
+    use super::KeyTable;
+
+    #[test]
+    fn test_shards_of() {
+        let table = KeyTable::new(4);
+        let keys: Vec<String> = (0..32).map(|key| std::format!("k{key}")).collect();
+        let shards = table.shards_of(keys.iter().map(String::as_str));
+        // sorted, distinct and every shard is used by some key
+        assert_eq!(shards, vec![0, 1, 2, 3]);
+        assert_eq!(
+            table.shards_of(["k1", "k1"]),
+            table.shards_of(["k1"]),
+            "a key always maps to the same shard"
+        );
+        assert_eq!(KeyTable::new(0).shards_of(["a", "b"]), vec![0]);
+    }
+
+    #[test]
+    fn test_key_guards() {
+        let table = KeyTable::new(8);
+        {
+            let mut guards = table.lock(["a", "b"]);
+            let _ = guards.shard_mut("a").active.insert(String::from("a"), 1);
+            guards
+                .shard_mut("b")
+                .waiting
+                .entry(String::from("b"))
+                .or_default()
+                .push_back(2);
+            assert!(guards.is_active("a"));
+            assert!(!guards.is_active("b"));
+            assert_eq!(
+                guards.waiting("b").and_then(|queue| queue.front()),
+                Some(&2)
+            );
+        }
+        let guards = table.lock_all();
+        assert_eq!(
+            guards
+                .shards()
+                .map(|shard| shard.active.len())
+                .sum::<usize>(),
+            1
+        );
+        assert!(table.try_lock_shards(&table.shards_of(["a"])).is_none());
+        drop(guards);
+        assert!(table.try_lock_shards(&table.shards_of(["a"])).is_some());
+    }
+}
@@ -1,175 +1,283 @@
-use std::cell::RefCell;
-use std::clone::Clone;
-use std::sync::Arc;
+/// define `Handle`, a node stays at its handle until it is removed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Handle(usize);
 
 /// define `ListNode`
 #[derive(Debug)]
-pub(crate) struct ListNode {
+struct ListNode {
     /// message id
-    message_id: i32,
-    /// right node pointer
-    next: Option<Arc<Cell>>,
-    /// left node pointer
-    prev: Option<Arc<Cell>>,
+    message_id: u64,
+    /// right node
+    next: Option<usize>,
+    /// left node
+    prev: Option<usize>,
 }
 
-/// define `Cell`
+/// define `Slot`, a slot of the slab holding the nodes
 #[derive(Debug)]
-pub(crate) struct Cell(RefCell<ListNode>);
-unsafe impl Sync for Cell {}
-unsafe impl Send for Cell {}
-/// define `List`
+enum Slot {
+    /// a node in the list
+    Used(ListNode),
+    /// a freed slot, linked to the next free one
+    Free(Option<usize>),
+}
+
+impl Slot {
+    /// the node of a used slot
+    fn node(&self) -> Option<&ListNode> {
+        match *self {
+            Slot::Used(ref node) => Some(node),
+            Slot::Free(_) => None,
+        }
+    }
+    /// the node of a used slot
+    fn node_mut(&mut self) -> Option<&mut ListNode> {
+        match *self {
+            Slot::Used(ref mut node) => Some(node),
+            Slot::Free(_) => None,
+        }
+    }
+}
+
+/// define `List`, a doubly linked list of message ids kept in a slab.
+/// removed nodes free their slot for the next push, so the slab only
+/// grows up to the most nodes queued at once
 #[derive(Debug)]
 pub(crate) struct List {
     /// record the size
     count: i32,
-    /// left head
-    first: Option<Arc<Cell>>,
-    /// right head
-    last: Option<Arc<Cell>>,
-}
-
-impl ListNode {
-    /// `new` create a Node
-    pub(crate) fn create_node(message_id: i32) -> Arc<Cell> {
-        Arc::new(Cell(RefCell::new(ListNode {
-            message_id,
-            next: None,
-            prev: None,
-        })))
-    }
+    /// the nodes and the freed slots
+    slots: Vec<Slot>,
+    /// the first freed slot
+    free: Option<usize>,
+    /// left end
+    first: Option<usize>,
+    /// right end
+    last: Option<usize>,
 }
 
 impl List {
     /// create a List
     pub(crate) fn new() -> List {
-        let first = ListNode::create_node(0);
-        let last = ListNode::create_node(0);
-        first.0.borrow_mut().next = Some(Arc::clone(&last));
-        last.0.borrow_mut().prev = Some(Arc::clone(&first));
         List {
             count: 0,
-            first: Some(first),
-            last: Some(last),
+            slots: Vec::new(),
+            free: None,
+            first: None,
+            last: None,
         }
     }
     /// get size of list
     pub(crate) fn list_count(&self) -> i32 {
         self.count
     }
-    /// get first node
-    pub(crate) fn list_first(&self) -> i32 {
-        if let Some(ref f) = self.first {
-            if let Some(ref rigth) = f.0.borrow().next {
-                return rigth.0.borrow().message_id;
-            }
-        }
-        -1
+    /// how many slots the slab holds, used or free
+    #[cfg(test)]
+    pub(crate) fn slots(&self) -> usize {
+        self.slots.len()
+    }
+    /// get first node, 0 means the list is empty
+    /// because message ids start from 1
+    pub(crate) fn list_first(&self) -> u64 {
+        self.first
+            .and_then(|index| self.node(index))
+            .map_or(0, |node| node.message_id)
+    }
+    /// get the message ids from the first node to the last one
+    pub(crate) fn list_ids(&self) -> Vec<u64> {
+        self.ids().collect()
+    }
+    /// walk the message ids from the first node to the last one
+    pub(crate) fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        let mut current = self.first;
+        std::iter::from_fn(move || {
+            let node = self.node(current?)?;
+            current = node.next;
+            Some(node.message_id)
+        })
     }
     /// push node in first place
-    pub(crate) fn list_push_first(&mut self, node: &Arc<Cell>) {
-        if let Some(ref f) = self.first {
-            let mut n = node.0.borrow_mut();
-            n.prev = Some(Arc::clone(f));
-            if let Some(ref p) = f.0.borrow().next {
-                n.next = Some(Arc::clone(p));
-                p.0.borrow_mut().prev = Some(Arc::clone(node));
-            };
-            f.0.borrow_mut().next = Some(Arc::clone(node));
-        };
-        self.count = self.count.wrapping_add(1);
+    pub(crate) fn list_push_first(&mut self, message_id: u64) -> Handle {
+        let index = self.alloc(message_id);
+        self.link_first(index);
+        Handle(index)
     }
     /// pust node at last
-    pub(crate) fn list_push_back(&mut self, node: &Arc<Cell>) {
-        if let Some(ref l) = self.last {
-            let mut n = node.0.borrow_mut();
-            n.next = Some(Arc::clone(l));
-            if let Some(ref p) = l.0.borrow().prev {
-                n.prev = Some(Arc::clone(p));
-                p.0.borrow_mut().next = Some(Arc::clone(node));
-            };
-            l.0.borrow_mut().prev = Some(Arc::clone(node));
-        };
-        self.count = self.count.wrapping_add(1);
+    pub(crate) fn list_push_back(&mut self, message_id: u64) -> Handle {
+        let index = self.alloc(message_id);
+        self.link_last(index);
+        Handle(index)
+    }
+    /// move a node to the first place, its handle stays the same
+    pub(crate) fn move_to_front(&mut self, handle: Handle) {
+        if self.node(handle.0).is_some() && self.first != Some(handle.0) {
+            self.unlink(handle.0);
+            self.link_first(handle.0);
+        }
     }
     /// pop first node
     #[allow(unused)]
-    pub(crate) fn list_pop_first(&mut self) -> i32 {
+    pub(crate) fn list_pop_first(&mut self) -> u64 {
         assert!((self.count != 0), "No Items for pop!");
-        let mut value = 0;
-        let mut pointer_pnext = None;
-        if let Some(ref f) = self.first {
-            if let Some(ref p) = f.0.borrow().next {
-                if let Some(ref pnext) = p.0.borrow().next {
-                    pointer_pnext = Some(Arc::clone(pnext));
-                    pnext.0.borrow_mut().prev = Some(Arc::clone(f));
-                }
-                value = p.0.borrow().message_id;
-            };
-            f.0.borrow_mut().next = pointer_pnext;
-        };
-        self.count = self.count.wrapping_sub(1);
-        value
+        self.first
+            .and_then(|index| self.remove(Handle(index)))
+            .unwrap_or(0)
     }
     /// pop last node
     #[allow(unused)]
-    pub(crate) fn list_pop_last(&mut self) -> i32 {
+    pub(crate) fn list_pop_last(&mut self) -> u64 {
         assert!((self.count != 0), "No Items for pop!");
-        let mut value = 0;
-        let mut pointer_pnext = None;
-        if let Some(ref l) = self.last {
-            if let Some(ref p) = l.0.borrow().prev {
-                if let Some(ref pnext) = p.0.borrow().prev {
-                    pointer_pnext = Some(Arc::clone(pnext));
-                    pnext.0.borrow_mut().next = Some(Arc::clone(l));
-                }
-                value = p.0.borrow().message_id;
-            };
-            l.0.borrow_mut().prev = pointer_pnext;
-        };
+        self.last
+            .and_then(|index| self.remove(Handle(index)))
+            .unwrap_or(0)
+    }
+    /// remove a node and free its slot, `None` means it was removed already
+    pub(crate) fn remove(&mut self, handle: Handle) -> Option<u64> {
+        let message_id = self.node(handle.0)?.message_id;
+        self.unlink(handle.0);
+        if let Some(slot) = self.slots.get_mut(handle.0) {
+            *slot = Slot::Free(self.free);
+        }
+        self.free = Some(handle.0);
         self.count = self.count.wrapping_sub(1);
-        value
-    }
-    /// remove a node
-    pub(crate) fn remove(&mut self, node: &Arc<Cell>) {
-        if let Some(ref left) = node.0.borrow().prev {
-            if let Some(ref right) = node.0.borrow().next {
-                left.0.borrow_mut().next = Some(Arc::clone(right));
-                right.0.borrow_mut().prev = Some(Arc::clone(left));
-                self.count = self.count.wrapping_sub(1);
+        if self.count == 0 {
+            // nothing is left to point into the slab
+            self.slots.clear();
+            self.free = None;
+        }
+        Some(message_id)
+    }
+
+    /// the node in a used slot
+    fn node(&self, index: usize) -> Option<&ListNode> {
+        self.slots.get(index).and_then(Slot::node)
+    }
+    /// the node in a used slot
+    fn node_mut(&mut self, index: usize) -> Option<&mut ListNode> {
+        self.slots.get_mut(index).and_then(Slot::node_mut)
+    }
+    /// put an unlinked node in a freed slot, or in a new one
+    fn alloc(&mut self, message_id: u64) -> usize {
+        let node = Slot::Used(ListNode {
+            message_id,
+            next: None,
+            prev: None,
+        });
+        self.count = self.count.wrapping_add(1);
+        if let Some(index) = self.free {
+            if let Some(slot) = self.slots.get_mut(index) {
+                if let Slot::Free(next_free) = *slot {
+                    self.free = next_free;
+                }
+                *slot = node;
+                return index;
             }
         }
+        self.slots.push(node);
+        self.slots.len().wrapping_sub(1)
+    }
+    /// link an unlinked node in first place
+    fn link_first(&mut self, index: usize) {
+        let old_first = self.first;
+        if let Some(node) = self.node_mut(index) {
+            node.prev = None;
+            node.next = old_first;
+        }
+        match old_first.and_then(|first| self.node_mut(first)) {
+            Some(first) => first.prev = Some(index),
+            None => self.last = Some(index),
+        }
+        self.first = Some(index);
+    }
+    /// link an unlinked node at last
+    fn link_last(&mut self, index: usize) {
+        let old_last = self.last;
+        if let Some(node) = self.node_mut(index) {
+            node.prev = old_last;
+            node.next = None;
+        }
+        match old_last.and_then(|last| self.node_mut(last)) {
+            Some(last) => last.next = Some(index),
+            None => self.first = Some(index),
+        }
+        self.last = Some(index);
+    }
+    /// take a node out of the links, its slot stays used
+    fn unlink(&mut self, index: usize) {
+        let Some(&ListNode { prev, next, .. }) = self.node(index) else {
+            return;
+        };
+        match prev.and_then(|left| self.node_mut(left)) {
+            Some(left) => left.next = next,
+            None => self.first = next,
+        }
+        match next.and_then(|right| self.node_mut(right)) {
+            Some(right) => right.prev = prev,
+            None => self.last = prev,
+        }
     }
 }
 
 #[cfg(test)]
 mod test_linked_test {
-    use super::{List, ListNode};
+    use super::List;
     #[test]
     fn test_linked_list() {
-        let node0 = ListNode::create_node(0);
-        let node1 = ListNode::create_node(1);
-        let node2 = ListNode::create_node(2);
         let mut list = List::new();
         // 1 <-> 0 <-> 2
-        list.list_push_first(&node0.clone());
-        list.list_push_first(&node1.clone());
-        list.list_push_back(&node2.clone());
+        let node0 = list.list_push_first(0);
+        let _ = list.list_push_first(1);
+        let _ = list.list_push_back(2);
         let res0 = list.list_pop_first();
         assert_eq!(res0, 1);
         assert_eq!(list.list_count(), 2);
-        let _ = list.remove(&node0.clone());
+        assert_eq!(list.remove(node0), Some(0));
+        assert_eq!(list.remove(node0), None);
         assert_eq!(list.list_count(), 1);
         let res2 = list.list_pop_first();
         assert_eq!(res2, 2);
         assert_eq!(list.list_count(), 0);
         // 0 <-> 1
-        list.list_push_back(&node0.clone());
-        list.list_push_back(&node1.clone());
+        let _ = list.list_push_back(0);
+        let node1 = list.list_push_back(1);
         let res3 = list.list_pop_last();
         assert_eq!(res3, 1);
         assert_eq!(list.list_count(), 1);
-        list.remove(&node1.clone());
+        assert_eq!(list.remove(node1), None);
+        assert_eq!(list.list_pop_first(), 0);
         assert_eq!(list.list_count(), 0);
     }
+
+    #[test]
+    fn test_list_ids() {
+        let mut list = List::new();
+        assert!(list.list_ids().is_empty());
+        // 2 <-> 0 <-> 1
+        let node0 = list.list_push_back(0);
+        let node1 = list.list_push_back(1);
+        let _ = list.list_push_first(2);
+        assert_eq!(list.list_ids(), vec![2, 0, 1]);
+        let _ = list.remove(node0);
+        assert_eq!(list.list_ids(), vec![2, 1]);
+        list.move_to_front(node1);
+        assert_eq!(list.list_ids(), vec![1, 2]);
+        assert_eq!(list.list_first(), 1);
+    }
+
+    #[test]
+    fn test_slots_are_reused() {
+        let mut list = List::new();
+        let held: Vec<_> = (1..=4).map(|id| list.list_push_back(id)).collect();
+        for id in 5..2_000_000 {
+            let handle = list.list_push_first(id);
+            list.move_to_front(handle);
+            assert_eq!(list.remove(handle), Some(id));
+        }
+        // the freed slot is taken again by every push
+        assert_eq!(list.slots(), 5);
+        for handle in held {
+            let _ = list.remove(handle);
+        }
+        assert_eq!(list.slots(), 0);
+    }
 }
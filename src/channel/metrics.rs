@@ -0,0 +1,172 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// how many buckets a latency histogram has
+pub(crate) const HISTOGRAM_BUCKETS: usize = 32;
+
+/// `WaitStats` shows how long messages wait in the channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct WaitStats {
+    /// how long the oldest queued message has been waiting
+    pub longest_queued_wait: Duration,
+    /// the longest time a recieved message waited before it was given out
+    pub longest_delivered_wait: Duration,
+}
+
+/// `HistogramSnapshot` is a copy of a latency histogram
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct HistogramSnapshot {
+    /// `buckets[0]` counts the durations below 1µs, `buckets[i]` counts
+    /// the ones in [2^(i-1), 2^i) µs, the last bucket counts all longer ones
+    pub buckets: Vec<u64>,
+    /// how many durations are recorded
+    pub count: u64,
+    /// the longest recorded duration
+    pub max: Duration,
+}
+
+/// `ChannelStats` is a snapshot of what is going on in a channel
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct ChannelStats {
+    /// messages waiting in the channel
+    pub queued: usize,
+    /// queued messages which can be recieved now
+    pub eligible: usize,
+    /// queued messages blocked by their keys
+    pub blocked: usize,
+    /// keys held by recieved messages
+    pub active_keys: usize,
+    /// messages sent into the channel since it was created
+    pub sent: u64,
+    /// messages given out by the channel since it was created
+    pub recieved: u64,
+    /// messages the channel refused to take
+    pub rejected: u64,
+    /// messages dropped by the overflow policy of a full channel
+    pub dropped: u64,
+    /// the total weight of the queued messages of a weighted channel
+    pub weight: usize,
+    /// the longest waits
+    pub wait: WaitStats,
+    /// time from sending a message to giving it out
+    pub delivery_latency: HistogramSnapshot,
+    /// time from giving a message out to releasing all its keys
+    pub hold_time: HistogramSnapshot,
+}
+
+/// `Histogram` records durations into power of two microsecond buckets
+/// with relaxed atomics, so recording is cheap and never locks
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    /// see `HistogramSnapshot::buckets`
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    /// the longest recorded duration in nanoseconds
+    max_nanos: AtomicU64,
+}
+
+impl Histogram {
+    /// create an empty histogram
+    pub(crate) fn new() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            max_nanos: AtomicU64::new(0),
+        }
+    }
+
+    /// record a duration
+    pub(crate) fn record(&self, duration: Duration) {
+        let micros = u64::try_from(duration.as_micros()).unwrap_or(u64::MAX);
+        let index = usize::try_from(u64::BITS.wrapping_sub(micros.leading_zeros()))
+            .unwrap_or(usize::MAX)
+            .min(HISTOGRAM_BUCKETS.wrapping_sub(1));
+        if let Some(bucket) = self.buckets.get(index) {
+            let _ = bucket.fetch_add(1, Ordering::Relaxed);
+        }
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let _ = self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    /// the longest recorded duration
+    pub(crate) fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed))
+    }
+
+    /// copy the histogram out
+    pub(crate) fn snapshot(&self) -> HistogramSnapshot {
+        let buckets: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        HistogramSnapshot {
+            count: buckets
+                .iter()
+                .fold(0, |sum, count| sum.saturating_add(*count)),
+            buckets,
+            max: self.max(),
+        }
+    }
+}
+
+/// `ChannelMetrics` holds the counters behind `ChannelStats`
+#[derive(Debug)]
+pub(crate) struct ChannelMetrics {
+    /// messages sent into the channel
+    pub(crate) sent: AtomicU64,
+    /// messages given out by the channel
+    pub(crate) recieved: AtomicU64,
+    /// messages the channel refused to take
+    pub(crate) rejected: AtomicU64,
+    /// messages dropped by the overflow policy
+    pub(crate) dropped: AtomicU64,
+    /// time from sending a message to giving it out
+    pub(crate) delivery_latency: Histogram,
+    /// time from giving a message out to releasing all its keys
+    pub(crate) hold_time: Histogram,
+}
+
+impl ChannelMetrics {
+    /// create metrics with every counter at 0
+    pub(crate) fn new() -> ChannelMetrics {
+        ChannelMetrics {
+            sent: AtomicU64::new(0),
+            recieved: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            delivery_latency: Histogram::new(),
+            hold_time: Histogram::new(),
+        }
+    }
+
+    /// add `count` to a counter
+    pub(crate) fn add(counter: &AtomicU64, count: usize) {
+        let count = u64::try_from(count).unwrap_or(u64::MAX);
+        let _ = counter.fetch_add(count, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::Histogram;
+    use std::time::Duration;
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::new();
+        histogram.record(Duration::ZERO);
+        histogram.record(Duration::from_micros(1));
+        histogram.record(Duration::from_micros(3));
+        histogram.record(Duration::from_secs(1_000_000));
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 4);
+        assert_eq!(snapshot.buckets.first(), Some(&1));
+        assert_eq!(snapshot.buckets.get(1), Some(&1));
+        assert_eq!(snapshot.buckets.get(2), Some(&1));
+        assert_eq!(snapshot.buckets.last(), Some(&1));
+        assert_eq!(snapshot.max, Duration::from_secs(1_000_000));
+    }
+}
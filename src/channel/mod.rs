@@ -1,4 +1,31 @@
+/// `bridge` is used to export `bridge` package
+pub(crate) mod bridge;
+/// `contention` is used to export `contention` package
+pub(crate) mod contention;
+/// `detached` is used to export `detached` package
+pub(crate) mod detached;
+/// `inbox` is used to export `inbox` package
+mod inbox;
+/// `key_table` is used to export `key_table` package
+mod key_table;
 /// `linked_list` is used to export `linked_list` package
 mod linked_list;
+/// `metrics` is used to export `metrics` package
+pub(crate) mod metrics;
 /// `mspc_channel` is used to export `mspc_channel` package
 pub(crate) mod mspc_channel;
+/// `overflow` is used to export `overflow` package
+pub(crate) mod overflow;
+/// `select` is used to export `select` package
+pub(crate) mod select;
+/// `shm` is used to export `shm` package
+#[cfg(target_os = "linux")]
+pub(crate) mod shm;
+/// `snapshot` is used to export `snapshot` package
+pub(crate) mod snapshot;
+/// `status` is used to export `status` package
+pub(crate) mod status;
+/// `wal` is used to export `wal` package
+pub(crate) mod wal;
+/// `weight` is used to export `weight` package
+pub(crate) mod weight;
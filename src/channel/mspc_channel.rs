@@ -1,61 +1,149 @@
+use super::contention::{HotKey, HotKeySketch, HOT_KEY_CAPACITY};
+use super::detached::{DetachedMessage, MessageMetadata};
+use super::inbox::Inbox;
+use super::key_table::{KeyGuards, KeyTable, DEFAULT_SHARDS};
+use super::linked_list::{Handle, List};
+use super::metrics::{ChannelMetrics, ChannelStats, WaitStats};
+use super::overflow::{DropHandler, OverflowPolicy};
+use super::select::Signal;
+use super::snapshot::{ActiveKey, ChannelSnapshot, QueuedMessage};
+use super::status::{CompletedHistory, MessageStatus, COMPLETED_HISTORY_SIZE};
+use super::wal::{LoggedMessage, Persist, Record, WriteAheadLog};
+use super::weight::Weigher;
 use crate::errors::{Errors, Result};
-use parking_lot::{Condvar, Mutex, RwLock};
+use log::{debug, error, trace};
+use parking_lot::{Condvar, Mutex, MutexGuard, RwLock};
 use std::{
-    cell::RefCell,
-    collections::{HashMap, HashSet},
-    fmt::Debug,
-    sync::Arc,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fmt::{self, Debug},
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant, SystemTime},
 };
-/// `CellMap` used to support concurrent channel
-#[derive(Debug)]
-pub(crate) struct CellMap<K, V>(RefCell<HashMap<K, V>>);
-unsafe impl<K, V> Sync for CellMap<K, V> {}
-unsafe impl<K, V> Send for CellMap<K, V> {}
 
-use super::linked_list::{Cell, List, ListNode};
+/// a valid message waiting longer than this is given out first by default
+pub(crate) const DEFAULT_AGING_THRESHOLD: Duration = Duration::from_millis(100);
+/// how many of the oldest messages `next_message` looks at
+const AGING_SCAN_LIMIT: usize = 64;
 /// Key is a struct type, we use it as the
-/// message's key
+/// message's key. the channel owns its queued messages, so their keys
+/// only hold a `Weak` to it
 #[derive(Debug)]
-pub(crate) struct Key<T>(String, Option<Arc<MspcChannel<T>>>);
+pub(crate) struct Key<T>(String, Option<Weak<MspcChannel<T>>>);
 
 impl<T> Drop for Key<T> {
     fn drop(&mut self) {
-        // get write_guard
-        if let Some(ref channel) = self.1 {
-            let mut write_guard = channel.counter.write();
-            // write_guard
-            let _ = write_guard.remove(&self.0);
-            // update key in duplicate
-            let mut write_guard_in_duplicate = channel.key_message_id_in_duplicate.0.borrow_mut();
-            // update ref_count
-            let mut write_ref_count = channel.id_to_message.0.borrow_mut();
-            // get list_guard
-            let mut list_guard = channel.cached_messages.lock();
-            // get id_to_node guard
-            let id_to_node_guard = channel.id_to_node.0.borrow_mut();
-            let set_opt = write_guard_in_duplicate.get(&self.0);
-            if let Some(set) = set_opt {
-                for id in set.iter() {
-                    let message_opt = write_ref_count.get_mut(id);
-                    if let Some(message_) = message_opt {
-                        let mut ref_guard = message_.ref_count.write();
-                        *ref_guard = ref_guard.wrapping_sub(1);
-                        if *ref_guard == 0 {
-                            let node_opt = id_to_node_guard.get(id);
-                            if let Some(node) = node_opt {
-                                list_guard.remove(&Arc::<Cell>::clone(node));
-                                // no_duplicate_key will be first
-                                list_guard.list_push_first(&Arc::<Cell>::clone(node));
-                            }
-                        }
+        // once every `Sender` and `Reciever` is gone no message waits for the key
+        if let Some(channel) = self.1.as_ref().and_then(Weak::upgrade) {
+            let mut shards = channel.keys.lock([self.0.as_str()]);
+            let mut state = channel.lock_state();
+            // only the recieved message holding this key can release it
+            if let Some(active_id) = shards.shard_mut(&self.0).active.remove(&self.0) {
+                trace!(id = active_id, key = self.0.as_str(); "key released");
+                if let Some(ref handler) = *channel.release_handler.read() {
+                    (handler.0)(&self.0);
+                }
+                channel.log_record(&Record::Release(active_id, String::from(&self.0)));
+                if let Some(active) = state.active_ids.get_mut(&active_id) {
+                    let _ = active.keys.remove(&self.0);
+                    if active.keys.is_empty() {
+                        channel.metrics.hold_time.record(active.since.elapsed());
+                        let _ = state.active_ids.remove(&active_id);
+                        channel.completed_history.lock().push(active_id);
                     }
                 }
+                // the first message waiting for this key is not blocked by it any more
+                let next_id = shards.waiting(&self.0).and_then(VecDeque::front).copied();
+                if let Some(next_id) = next_id {
+                    channel.unblock(&mut state, next_id, &self.0);
+                }
             }
-            let _ = write_guard_in_duplicate.remove(&self.0);
         }
     }
 }
 
+/// `ReleaseHandler` is told about every key released by a recieved message
+pub(crate) struct ReleaseHandler(Box<dyn Fn(&str) + Send + Sync>);
+
+impl Debug for ReleaseHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReleaseHandler")
+    }
+}
+
+/// `ActiveMessage` records a recieved message still holding keys
+#[derive(Debug)]
+pub(crate) struct ActiveMessage {
+    /// the keys it still holds
+    keys: HashSet<String>,
+    /// when it was given out
+    since: Instant,
+}
+
+/// `State` is what the channel knows about its queued and recieved
+/// messages apart from their keys, it is only reached through the `state`
+/// lock of the channel
+#[derive(Debug)]
+pub(crate) struct State<T> {
+    /// the queued message ids, valid messages in front
+    list: List,
+    /// queued message id -> its node in `list`
+    id_to_node: HashMap<u64, Handle>,
+    /// queued message id -> the message
+    id_to_message: HashMap<u64, InternalMessage<T>>,
+    /// recieved message id -> keys it still holds
+    active_ids: HashMap<u64, ActiveMessage>,
+    /// queued message `seq` -> its id and when it was pushed, so the
+    /// first entry is the message pushed earliest
+    enqueue_times: BTreeMap<u64, (u64, Instant)>,
+}
+
+impl<T> State<T> {
+    /// create the state of an empty channel
+    fn new() -> State<T> {
+        State {
+            list: List::new(),
+            id_to_node: HashMap::new(),
+            id_to_message: HashMap::new(),
+            active_ids: HashMap::new(),
+            enqueue_times: BTreeMap::new(),
+        }
+    }
+
+    /// how many messages are queued
+    pub(crate) fn queued(&self) -> i32 {
+        self.list.list_count()
+    }
+
+    /// check a queued message is valid, none of its keys blocks it
+    pub(crate) fn is_valid(&self, message_id: u64) -> bool {
+        self.id_to_message
+            .get(&message_id)
+            .is_some_and(|message| message.ref_count == 0)
+    }
+
+    /// the keys of the queued messages `message_ids`
+    fn keys_of<'a>(&'a self, message_ids: &'a [u64]) -> impl Iterator<Item = &'a str> {
+        message_ids
+            .iter()
+            .filter_map(|id| self.id_to_message.get(id))
+            .flat_map(|message| message.keys.iter().map(|key| key.0.as_str()))
+    }
+}
+
+/// `Durability` writes what happens to the messages of a durable channel
+#[derive(Debug)]
+pub(crate) struct Durability<T> {
+    /// the write ahead log
+    log: Mutex<WriteAheadLog>,
+    /// encode the data of a message
+    encode: fn(&T) -> Vec<u8>,
+}
+
 /// `InternalMessage` is a struct which is used
 /// to passed by channel
 #[derive(Debug)]
@@ -64,66 +152,173 @@ pub struct InternalMessage<T> {
     keys: Vec<Key<T>>,
     /// a message data
     pub data: T,
-    /// duplicate times
-    ref_count: RwLock<i32>,
-    /// message_id
-    id: i32,
+    /// how many of its keys block it, a key blocks the message when
+    /// the key is active or an earlier message with the key is queued
+    ref_count: i32,
+    /// `message_id`
+    id: u64,
+    /// where it was pushed in the queue, given by the inbox of the
+    /// channel. unlike the id it follows the order the messages are
+    /// recieved in
+    seq: u64,
+    /// when a channel took it, `None` before sending
+    sent_at: Option<SystemTime>,
 }
 
 impl<T> InternalMessage<T> {
-    /// `new` is used to generate a `InternalMessage`
+    /// `new` is used to generate a `InternalMessage`, a key
+    /// appearing more than once only counts once
     pub fn new(vecs: Vec<String>, data_: T) -> InternalMessage<T> {
         let mut message = InternalMessage {
             keys: Vec::<Key<T>>::new(),
             data: data_,
-            ref_count: RwLock::new(0),
+            ref_count: 0,
             id: 0,
+            seq: 0,
+            sent_at: None,
         };
         for vec in vecs {
-            message.keys.push(Key(vec, None));
+            if !message.keys.iter().any(|key| key.0 == vec) {
+                message.keys.push(Key(vec, None));
+            }
         }
         message
     }
+
+    /// the names of its keys, used for logging
+    pub(crate) fn key_names(&self) -> Vec<&str> {
+        self.keys.iter().map(|key| key.0.as_str()).collect()
+    }
+
+    /// `id` returns the id given by the channel when the message is sent,
+    /// it is 0 before sending. ids are unique for the channel's lifetime
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// what the channel recorded about the message
+    pub(crate) fn metadata(&self) -> MessageMetadata {
+        MessageMetadata {
+            sent_at: self.sent_at,
+        }
+    }
+
+    /// `to_detached` copies the keys, data, id and metadata of the message.
+    /// the message keeps its keys, unlike moving its data out, which
+    /// releases them
+    pub fn to_detached(&self) -> DetachedMessage<T>
+    where
+        T: Clone,
+    {
+        DetachedMessage {
+            keys: self.key_names().into_iter().map(String::from).collect(),
+            data: self.data.clone(),
+            id: self.id,
+            metadata: self.metadata(),
+        }
+    }
 }
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 /// `Sender` is used to recieve message from channel.
 pub struct Sender<T> {
-    /// hold a channel ref_counter
+    /// hold a channel `ref_counter`
     chan: Arc<MspcChannel<T>>,
 }
 
+/// a cloned `Sender` shares the channel, whatever the message type is
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        Sender {
+            chan: Arc::clone(&self.chan),
+        }
+    }
+}
+
 impl<T> Sender<T> {
-    /// send a message to the channel
-    pub fn send(&self, mut message: InternalMessage<T>) -> Result<()> {
-        for key in &mut message.keys {
-            key.1 = Some(Arc::clone(&self.chan));
-        }
-        let mut mutex = self.chan.message_id.lock();
-        *mutex = mutex.wrapping_add(1);
-        message.id = *mutex;
-        drop(mutex);
-        // -1 means this is an unbounded channel
-        if self.chan.bounded_size == -1 {
-            self.chan.push_message(message);
-            Ok(())
-        } else {
-            loop {
-                // get lock make sure operations are atomic
-                let mut lock = self.chan.message_id.lock();
-                let mut write_guard = self.chan.cached_messages.lock();
-                if write_guard.list_count() < self.chan.bounded_size {
-                    drop(write_guard);
-                    self.chan.push_message(message);
-                    write_guard = self.chan.cached_messages.lock();
-                    if write_guard.list_count() == 1 {
-                        let _ = self.chan.cond_var_recieve.notify_one();
-                    }
-                    return Ok(());
-                }
-                // channel is full, wait here.
-                self.chan.cond_var_send.wait(&mut lock);
-            }
+    /// send a message to the channel, return the id of the message.
+    /// messages sharing any key are recieved in the order their `send`
+    /// calls finish, so the sends of one thread keep their order.
+    /// a full bounded channel handles it by its `OverflowPolicy`, and
+    /// a durable channel fails when the message can not be logged
+    pub fn send(&self, mut message: InternalMessage<T>) -> Result<u64> {
+        let id = self.chan.next_message_id();
+        self.chan.bind(&mut message, id);
+        self.chan.enqueue(vec![message])?;
+        Ok(id)
+    }
+
+    /// `send_all` sends a batch of messages with one id allocation and one
+    /// critical section, and returns their ids in order. it is all-or-nothing
+    /// for a bounded channel: the overflow policy makes room for the whole
+    /// batch, and a batch larger than the capacity is rejected without
+    /// sending any
+    pub fn send_all<I>(&self, messages: I) -> Result<Vec<u64>>
+    where
+        I: IntoIterator<Item = InternalMessage<T>>,
+    {
+        let mut messages: Vec<InternalMessage<T>> = messages.into_iter().collect();
+        let size = i32::try_from(messages.len()).or(Err(Errors::ExceedCapacity))?;
+        if size == 0 {
+            return Ok(Vec::new());
+        }
+        let first_id = self.chan.next_message_ids(messages.len());
+        let mut ids = Vec::with_capacity(messages.len());
+        for (message, id) in messages.iter_mut().zip(first_id..) {
+            self.chan.bind(message, id);
+            ids.push(id);
         }
+        self.chan.enqueue(messages)?;
+        Ok(ids)
+    }
+
+    /// `status` tells the lifecycle status of the message with `id`
+    pub fn status(&self, id: u64) -> MessageStatus {
+        self.chan.status(id)
+    }
+
+    /// `wait_stats` tells how long messages wait in the channel
+    pub fn wait_stats(&self) -> WaitStats {
+        self.chan.wait_stats()
+    }
+
+    /// `stats` takes a snapshot of the channel's metrics
+    pub fn stats(&self) -> ChannelStats {
+        self.chan.stats()
+    }
+
+    /// `hot_keys` reports the `n` keys which blocked messages most often
+    pub fn hot_keys(&self, n: usize) -> Vec<HotKey> {
+        self.chan.hot_keys.lock().top(n)
+    }
+
+    /// `snapshot` shows the queued messages and what blocks them
+    pub fn snapshot(&self) -> ChannelSnapshot {
+        self.chan.snapshot()
+    }
+
+    /// `sync` flushes the log of a durable channel to the disk, records
+    /// survive a crash of the process once written, and a power loss
+    /// once synced. it does nothing for an in-memory channel
+    pub fn sync(&self) -> Result<()> {
+        match self.chan.durability {
+            Some(ref durability) => durability
+                .log
+                .lock()
+                .sync()
+                .or(Err(Errors::WriteAheadLogError)),
+            None => Ok(()),
+        }
+    }
+
+    /// `close` stops the channel taking messages, later sends fail with
+    /// `Closed` while the reciever still drains the queued messages
+    pub fn close(&self) {
+        let _ = self.chan.close(false);
+    }
+
+    /// `is_closed` tells whether the channel is closed
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
     }
 }
 
@@ -131,128 +326,1230 @@ impl<T> Sender<T> {
 #[deny(clippy::clone_on_ref_ptr)]
 /// `Reciever` is used to recieve message from channel.
 pub struct Reciever<T> {
-    /// hold a channel ref_counter
+    /// hold a channel `ref_counter`
     chan: Arc<MspcChannel<T>>,
 }
 
+impl<T> Reciever<T> {
+    /// get the channel, used by `Select`
+    pub(crate) fn chan(&self) -> &MspcChannel<T> {
+        &self.chan
+    }
+
+    /// `close` stops the channel taking messages, later sends fail with
+    /// `Closed`. the queued messages are still recieved, and `recv` fails
+    /// with `Closed` once they are gone
+    pub fn close(&self) {
+        let _ = self.chan.close(false);
+    }
+
+    /// `close_and_take` closes the channel and takes out every undelivered
+    /// message in the order their sends enqueued them, so each key keeps
+    /// its delivery order. recieved messages keep their keys until they
+    /// are dropped
+    pub fn close_and_take(&self) -> Vec<InternalMessage<T>> {
+        self.chan.close(true)
+    }
+
+    /// `pause` stops giving out messages while sends go on, `recv` waits
+    /// and `try_recv` fails with `Paused`. recieved messages still release
+    /// their keys
+    pub fn pause(&self) {
+        self.chan.set_paused(true);
+    }
+
+    /// `resume` gives out messages again and wakes the waiting reciever
+    pub fn resume(&self) {
+        self.chan.set_paused(false);
+    }
+
+    /// `is_paused` tells whether the channel is paused
+    pub fn is_paused(&self) -> bool {
+        self.chan.is_paused()
+    }
+
+    /// `is_closed` tells whether the channel is closed
+    pub fn is_closed(&self) -> bool {
+        self.chan.is_closed()
+    }
+}
+
 impl<T> Reciever<T>
 where
     T: Debug,
 {
-    /// `recv` recieve message from channel
+    /// `recv` recieve message from channel, a message is only given out
+    /// when none of its keys is active and every earlier message sharing
+    /// a key with it has been recieved
     pub fn recv(&self) -> Result<InternalMessage<T>> {
         // get write_guard
         loop {
-            let mut write_guard = self.chan.cached_messages.lock();
+            let mut state = self.chan.lock_state();
             // 1.there is no message in channel
             // just loop ahead
-            if write_guard.list_count() == 0 {
-                self.chan.cond_var_recieve.wait(&mut write_guard);
+            if state.queued() == 0 {
+                if self.chan.is_closed() {
+                    return Err(Errors::Closed);
+                }
+                trace!("reciever waiting for messages");
+                self.chan.wait_for_send(&mut state);
+                continue;
+            }
+            if self.chan.is_paused() {
+                trace!("reciever waiting for resume");
+                self.chan.cond_var_recieve.wait(&mut state);
                 continue;
             }
 
-            // 2.check is there a valid message
-            let message_id = write_guard.list_first();
-            let size = write_guard.list_count();
-            drop(write_guard);
+            // 2.check is there a valid message, an aged one goes first
+            let message_id = self.chan.next_message(&state);
             // if valid, we should give it out
-            if self.chan.is_valid(message_id) {
+            if state.is_valid(message_id) {
                 // do some necessary update
-                let message = self.chan.remove(message_id);
-                if 1 == self.chan.bounded_size.wrapping_sub(size) {
-                    let _ = self.chan.cond_var_send.notify_all();
+                if let Some(message) = self.chan.give_out(state, &[message_id]).pop() {
+                    self.chan.notify_senders();
+                    return Ok(message);
                 }
-                return Ok(message);
+                // it was dropped before its keys were locked, look again
+                continue;
             }
             // otherwise, all messages are duplicated,
             // return error
             return Err(Errors::KeyDuplicate);
         }
     }
+
+    /// `try_recv` is `recv` without waiting, it fails with `Paused` while
+    /// the channel is paused, with `Empty` when no message is queued and
+    /// with `Closed` when the channel is closed and empty
+    pub fn try_recv(&self) -> Result<InternalMessage<T>> {
+        loop {
+            let state = self.chan.lock_state();
+            if state.queued() == 0 && self.chan.is_closed() {
+                return Err(Errors::Closed);
+            }
+            if self.chan.is_paused() {
+                return Err(Errors::Paused);
+            }
+            if state.queued() == 0 {
+                return Err(Errors::Empty);
+            }
+            let message_id = self.chan.next_message(&state);
+            if !state.is_valid(message_id) {
+                return Err(Errors::KeyDuplicate);
+            }
+            // it may be dropped before its keys are locked, then look again
+            if let Some(message) = self.chan.give_out(state, &[message_id]).pop() {
+                self.chan.notify_senders();
+                return Ok(message);
+            }
+        }
+    }
+
+    /// `recv_batch` recieves up to `max` valid messages in one critical
+    /// section, their keys are all marked active together. valid messages
+    /// never share a key, because only the first waiting message of a key
+    /// can be valid, so the key sets of the batch are pairwise disjoint
+    pub fn recv_batch(&self, max: usize) -> Result<Vec<InternalMessage<T>>> {
+        let mut messages = Vec::new();
+        if max == 0 {
+            return Ok(messages);
+        }
+        loop {
+            let mut state = self.chan.lock_state();
+            // 1.there is no message in channel
+            // just loop ahead
+            if state.queued() == 0 {
+                if self.chan.is_closed() {
+                    return Err(Errors::Closed);
+                }
+                trace!("reciever waiting for messages");
+                self.chan.wait_for_send(&mut state);
+                continue;
+            }
+            if self.chan.is_paused() {
+                trace!("reciever waiting for resume");
+                self.chan.cond_var_recieve.wait(&mut state);
+                continue;
+            }
+
+            // 2.take valid messages until the batch is full
+            let message_ids = self.chan.next_messages(&state, max);
+            if message_ids.is_empty() {
+                // all messages are duplicated
+                return Err(Errors::KeyDuplicate);
+            }
+            messages = self.chan.give_out(state, &message_ids);
+            if messages.is_empty() {
+                // they were dropped before their keys were locked
+                continue;
+            }
+            self.chan.notify_senders();
+            return Ok(messages);
+        }
+    }
+
+    /// `status` tells the lifecycle status of the message with `id`
+    pub fn status(&self, id: u64) -> MessageStatus {
+        self.chan.status(id)
+    }
+
+    /// `wait_stats` tells how long messages wait in the channel
+    pub fn wait_stats(&self) -> WaitStats {
+        self.chan.wait_stats()
+    }
+
+    /// `stats` takes a snapshot of the channel's metrics
+    pub fn stats(&self) -> ChannelStats {
+        self.chan.stats()
+    }
+
+    /// `hot_keys` reports the `n` keys which blocked messages most often
+    pub fn hot_keys(&self, n: usize) -> Vec<HotKey> {
+        self.chan.hot_keys.lock().top(n)
+    }
+
+    /// `snapshot` shows the queued messages and what blocks them
+    pub fn snapshot(&self) -> ChannelSnapshot {
+        self.chan.snapshot()
+    }
+
+    /// `set_aging_threshold` changes how long a valid message may wait
+    /// before it is given out ahead of newer valid messages. a waiting
+    /// message always reserves its keys, so newer messages with the same
+    /// keys wait behind it whatever the threshold is
+    pub fn set_aging_threshold(&self, threshold: Duration) {
+        *self.chan.aging_threshold.write() = threshold;
+    }
+
+    /// `capacity` is the most messages the channel holds, -1 means it is
+    /// unbounded
+    pub fn capacity(&self) -> i32 {
+        self.chan.bounded_size()
+    }
+
+    /// `set_capacity` changes the most messages the channel holds, a
+    /// negative capacity makes it unbounded. blocked senders are woken up
+    /// at once when it grows, and when it shrinks the queued messages stay
+    /// while new sends wait for the new bound
+    pub fn set_capacity(&self, capacity: i32) {
+        self.chan.set_bounded_size(capacity);
+    }
+
+    /// `set_key_capacity` limits how many queued messages may wait for one
+    /// key, a sender only meets the overflow policy when the channel or
+    /// the queue of one of its keys is full. `None` removes the limit
+    pub fn set_key_capacity(&self, capacity: Option<usize>) {
+        // a larger capacity may let waiting senders in
+        self.chan
+            .change_and_wake(|_| *self.chan.key_capacity.write() = capacity);
+    }
+
+    /// `set_drop_handler` is called with every message dropped by the
+    /// overflow policy, after the channel is unlocked
+    pub fn set_drop_handler<F>(&self, handler: F)
+    where
+        F: Fn(InternalMessage<T>) + Send + Sync + 'static,
+    {
+        *self.chan.drop_handler.write() = Some(Arc::new(DropHandler(Box::new(handler))));
+    }
+}
+
+/// `Room` tells what a sender does with messages after the overflow policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Room {
+    /// push them
+    Ready,
+    /// the channel is full and the policy drops them
+    Full,
+    /// wait for the reciever to make room
+    Wait,
 }
 
 /// `MspcChannel` is a multi producer and single consumer
 /// channel, we will use it to transfer message between
-/// threads
+/// threads.
+///
+/// locks are taken in this order: the shards of `keys` in ascending
+/// order, then `state`, then at most one of the other locks, which are
+/// leaves. the only nested leaves are `watchers` and the lock of a
+/// watching `Select`. the drop handler is called with no lock held.
+///
+/// a send locks only the shards of its keys: it takes room in `queued`
+/// and leaves the messages in `inbox`, which every holder of `state`
+/// moves into the queue first. a send still locks `state` when the
+/// channel is full, weighted or durable
 #[derive(Debug)]
 pub(crate) struct MspcChannel<T> {
-    /// the messages will be stored here
-    cached_messages: Arc<Mutex<List>>,
-    /// used for checking duplicate keys
-    counter: Arc<RwLock<HashSet<String>>>,
-    /// the capcity of a channel
-    bounded_size: i32,
+    /// the queues and holders of the keys, sharded by key
+    keys: KeyTable,
+    /// the queued and recieved messages
+    state: Mutex<State<T>>,
+    /// the sent messages not yet in `state`, with when they were sent
+    inbox: Inbox<(InternalMessage<T>, Instant)>,
+    /// the queued messages, with the ones in `inbox` and the room taken
+    /// by senders about to fill it. it only shrinks with `state` locked
+    queued: AtomicUsize,
+    /// how many recievers wait on `cond_var_recieve` for a send
+    recievers_waiting: AtomicUsize,
+    /// a closed channel takes no more messages
+    closed: AtomicBool,
+    /// a paused channel takes messages but gives none out
+    paused: AtomicBool,
+    /// the capcity of a channel, -1 means it is unbounded. changed with
+    /// `state` locked
+    bounded_size: AtomicI32,
+    /// bounds the total weight of the queued messages of a weighted channel
+    weigher: Option<Weigher<T>>,
+    /// the most messages queued for one key, `None` means no limit
+    key_capacity: RwLock<Option<usize>>,
+    /// what a full bounded channel or key queue does with a new message
+    overflow_policy: OverflowPolicy,
+    /// told about the messages dropped by `overflow_policy`
+    drop_handler: RwLock<Option<Arc<DropHandler<T>>>>,
+    /// called with every released key, under the locks of the channel
+    release_handler: RwLock<Option<ReleaseHandler>>,
     /// use condVar to support block recieve
     cond_var_recieve: Arc<Condvar>,
-    /// use condVar to support block send
+    /// use condVar to support block send, senders wait with `state` and
+    /// no shard locked
     cond_var_send: Arc<Condvar>,
-    /// global message_id
-    message_id: Mutex<i32>,
-    /// global map: id -> node
-    id_to_node: CellMap<i32, Arc<Cell>>,
-    /// global map: key -> [duplicate_message_id0,duplicate_messagey_id1,...]
-    key_message_id_in_duplicate: CellMap<String, HashSet<i32>>,
-    /// id to Message
-    id_to_message: CellMap<i32, InternalMessage<T>>,
-    /// golbal map: key -> [message_id0,message_id1,...]
-    key_to_message_id: CellMap<String, HashSet<i32>>,
+    /// global `message_id`, the last id given out
+    message_id: AtomicU64,
+    /// the latest completed message ids
+    completed_history: Mutex<CompletedHistory>,
+    /// the latest dropped message ids
+    dropped_history: Mutex<CompletedHistory>,
+    /// a valid message waiting longer than it is given out first
+    aging_threshold: RwLock<Duration>,
+    /// counters and histograms behind `stats`
+    metrics: ChannelMetrics,
+    /// the keys blocking messages most often
+    hot_keys: Mutex<HotKeySketch>,
+    /// signals of the `Select`s watching this channel
+    watchers: RwLock<Vec<Weak<Signal>>>,
+    /// the log of a durable channel
+    durability: Option<Durability<T>>,
 }
 
 impl<T> MspcChannel<T> {
-    /// check message is valid or not
-    pub(crate) fn is_valid(&self, message_id: i32) -> bool {
-        let read_guard = self.id_to_message.0.borrow();
-        if let Some(res) = read_guard.get(&message_id) {
-            let read_guard2 = res.ref_count.read();
-            return *read_guard2 == 0;
-        }
-        false
-    }
-
-    /// remove a message
-    pub(crate) fn remove(&self, message_id: i32) -> InternalMessage<T> {
-        // get lock
-        let mut list = self.cached_messages.lock();
-        let mut id_to_message_guard = self.id_to_message.0.borrow_mut();
-        let mut id_to_node_guard = self.id_to_node.0.borrow_mut();
-        let res = id_to_message_guard.remove(&message_id);
-        // remove message_id in list
-        if let Some(node) = id_to_node_guard.remove(&message_id) {
-            list.remove(&node);
+    /// allocate a new message id without locking, ids start from 1
+    /// and never wrap, so they are unique for the channel's lifetime
+    pub(crate) fn next_message_id(&self) -> u64 {
+        self.next_message_ids(1)
+    }
+
+    /// allocate `count` consecutive message ids at once, return the first
+    pub(crate) fn next_message_ids(&self, count: usize) -> u64 {
+        let count = u64::try_from(count).unwrap_or(u64::MAX);
+        let last = self
+            .message_id
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |id| {
+                id.checked_add(count)
+            });
+        match last {
+            Ok(id) => id.wrapping_add(1),
+            Err(last_id) => panic!("message ids are exhausted after {last_id}"),
         }
-        if let Some(message_0) = res {
-            let mut write_counter = self.counter.write();
-            let mut write_message_id = self.key_to_message_id.0.borrow_mut();
-            let mut write_message_id_duplicate = self.key_message_id_in_duplicate.0.borrow_mut();
-            for i in 0..message_0.keys.len() {
-                if let Some(key_) = message_0.keys.get(i) {
-                    // update counter
-                    let _ = write_counter.insert(String::from(&key_.0));
-                    // get duplicate_set
-                    let duplicate_set = write_message_id_duplicate
-                        .entry(String::from(&key_.0))
-                        .or_insert_with(HashSet::new);
-                    //  update key_to_message_id
-                    if let Some(set) = write_message_id.get_mut(&key_.0) {
-                        let _ = set.remove(&message_id);
-                        // update key_message_id_in_duplicate
-
-                        for id in set.iter() {
-                            let _ = duplicate_set.insert(*id);
-                            let message_opt = id_to_message_guard.get(id);
-                            if let Some(message) = message_opt {
-                                // update ref_count
-                                let mut ref_count_guard = message.ref_count.write();
-                                *ref_count_guard = ref_count_guard.wrapping_add(1);
-                                // change the list
-                                if let Some(node) = id_to_node_guard.get(id) {
-                                    list.remove(&Arc::<Cell>::clone(node));
-                                    list.list_push_back(&Arc::<Cell>::clone(node));
-                                }
-                            }
+    }
+
+    /// give a message its id and let its keys know the channel
+    pub(crate) fn bind(self: &Arc<Self>, message: &mut InternalMessage<T>, id: u64) {
+        for key in &mut message.keys {
+            key.1 = Some(Arc::downgrade(self));
+        }
+        message.id = id;
+        message.sent_at = Some(SystemTime::now());
+    }
+
+    /// detach the keys of a message which was never recieved, so dropping
+    /// it does not release the keys held by other messages
+    fn unbind(message: &mut InternalMessage<T>) {
+        for key in &mut message.keys {
+            key.1 = None;
+        }
+    }
+
+    /// enqueue messages bound to the channel, all of them or none. the
+    /// drop handler is called after `state` is unlocked
+    pub(crate) fn enqueue(&self, mut messages: Vec<InternalMessage<T>>) -> Result<()> {
+        // dropping an old message may release the keys of any shard
+        let every_shard = matches!(
+            self.overflow_policy,
+            OverflowPolicy::DropOldest | OverflowPolicy::DropOldestSameKey
+        );
+        let mut dropped = Vec::new();
+        let mut pushed = false;
+        let result = loop {
+            let mut shards = if every_shard {
+                self.keys.lock_all()
+            } else {
+                self.keys.lock(
+                    messages
+                        .iter()
+                        .flat_map(|message| message.keys.iter().map(|key| key.0.as_str())),
+                )
+            };
+            // whether a key is free is decided under its shard alone
+            let mut blocked = Self::blocked_keys(&shards, &messages);
+            match self.reserve_without_state(&shards, &messages) {
+                Ok(true) => {
+                    self.stage(&mut shards, messages.drain(..), blocked);
+                    pushed = true;
+                    break Ok(());
+                }
+                Ok(false) => {}
+                Err(err) => break Err(err),
+            }
+            let mut state = self.lock_state();
+            let already_dropped = dropped.len();
+            match self.make_room(&mut shards, &mut state, &messages, &mut dropped) {
+                Ok(Room::Ready) => {
+                    if dropped.len() != already_dropped {
+                        // the dropped messages may have held the keys
+                        blocked = Self::blocked_keys(&shards, &messages);
+                    }
+                    // the log follows the queue order, so it is written under `state`
+                    if let Err(err) = self.log_sends(&messages) {
+                        self.free_room(messages.len());
+                        break Err(err);
+                    }
+                    self.stage(&mut shards, messages.drain(..), blocked);
+                    pushed = true;
+                    break Ok(());
+                }
+                Ok(Room::Full) => {
+                    for message in &mut messages {
+                        Self::unbind(message);
+                        self.record_dropped(message);
+                    }
+                    dropped.append(&mut messages);
+                    break Ok(());
+                }
+                Ok(Room::Wait) => {
+                    // channel or key queue is full, wait here. the shards
+                    // are unlocked so the reciever can make room
+                    drop(shards);
+                    trace!(needed = messages.len(), queued = state.queued(); "sender waiting for room");
+                    self.cond_var_send.wait(&mut state);
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        // the messages which were not enqueued never held their keys
+        for message in &mut messages {
+            Self::unbind(message);
+        }
+        if pushed {
+            self.wake_reciever();
+        }
+        self.report_dropped(dropped);
+        result
+    }
+
+    /// take room for `messages` with only the shards of their keys locked,
+    /// `Ok(false)` means the sender needs `state`: the channel is full, or
+    /// it is weighted or durable, whose sends are ordered by `state`
+    fn reserve_without_state(
+        &self,
+        shards: &KeyGuards<'_>,
+        messages: &[InternalMessage<T>],
+    ) -> Result<bool> {
+        if self.weigher.is_some() || self.durability.is_some() {
+            return Ok(false);
+        }
+        self.check_open(messages)?;
+        self.check_capacity(messages)?;
+        Ok(self.saturated_key(shards, messages).is_none() && self.reserve(messages.len()))
+    }
+
+    /// take room for `count` messages when the bound leaves it
+    fn reserve(&self, count: usize) -> bool {
+        self.queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+                let total = queued.checked_add(count)?;
+                let bounded_size = self.bounded_size();
+                let fits = bounded_size == -1
+                    || usize::try_from(bounded_size).is_ok_and(|bound| total <= bound);
+                fits.then_some(total)
+            })
+            .is_ok()
+    }
+
+    /// take room for `count` messages over the bound
+    fn take_room(&self, count: usize) {
+        let _ = self.queued.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// give back the room of `count` messages, `state` is locked so the
+    /// waiting senders see it
+    fn free_room(&self, count: usize) {
+        let _ = self.queued.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    /// make room for `messages` in a full bounded channel by the overflow
+    /// policy, the shards of their keys and `state` are locked. the room
+    /// is taken for `Room::Ready`. dropped queued messages are moved to
+    /// `dropped`, every shard is locked for the policies dropping them
+    fn make_room(
+        &self,
+        shards: &mut KeyGuards<'_>,
+        state: &mut State<T>,
+        messages: &[InternalMessage<T>],
+        dropped: &mut Vec<InternalMessage<T>>,
+    ) -> Result<Room> {
+        self.check_open(messages)?;
+        self.check_capacity(messages)?;
+        if self.has_room(shards, messages) && self.reserve(messages.len()) {
+            return Ok(Room::Ready);
+        }
+        match self.overflow_policy {
+            OverflowPolicy::Block => Ok(Room::Wait),
+            OverflowPolicy::Reject => {
+                ChannelMetrics::add(&self.metrics.rejected, messages.len());
+                Err(Errors::ExceedCapacity)
+            }
+            OverflowPolicy::DropNewest => Ok(Room::Full),
+            OverflowPolicy::DropOldest | OverflowPolicy::DropOldestSameKey => {
+                let same_key = self.overflow_policy == OverflowPolicy::DropOldestSameKey;
+                while !(self.has_room(shards, messages) && self.reserve(messages.len())) {
+                    // a full key queue only gets room by dropping one of its messages
+                    let victim = self
+                        .saturated_key(shards, messages)
+                        .and_then(|key| shards.waiting(key)?.front().copied())
+                        .or_else(|| {
+                            same_key
+                                .then(|| Self::oldest_sharing_key(shards, messages))
+                                .flatten()
+                        });
+                    // messages of other keys are never dropped for the same key policy
+                    let victim = if same_key {
+                        victim
+                    } else {
+                        victim.or_else(|| Self::oldest_queued(state))
+                    };
+                    match victim {
+                        Some(id) => dropped.extend(self.drop_queued(shards, state, id)),
+                        None if same_key => return Ok(Room::Full),
+                        None => {
+                            self.take_room(messages.len());
+                            break;
                         }
                     }
                 }
+                Ok(Room::Ready)
+            }
+        }
+    }
+
+    /// whether `messages` fit in the channel, in its weight and in the
+    /// queues of their keys
+    fn has_room(&self, shards: &KeyGuards<'_>, messages: &[InternalMessage<T>]) -> bool {
+        let bounded_size = self.bounded_size();
+        let fits = bounded_size == -1
+            || usize::try_from(bounded_size).is_ok_and(|bound| {
+                self.queued
+                    .load(Ordering::Relaxed)
+                    .saturating_add(messages.len())
+                    <= bound
+            });
+        let light = self.weigher.as_ref().is_none_or(|weigher| {
+            weigher.fits(weigher.weigh(messages.iter().map(|message| &message.data)))
+        });
+        fits && light && self.saturated_key(shards, messages).is_none()
+    }
+
+    /// a key of `messages` whose queue has no room for them under the
+    /// per-key capacity
+    fn saturated_key<'a>(
+        &self,
+        shards: &KeyGuards<'_>,
+        messages: &'a [InternalMessage<T>],
+    ) -> Option<&'a str> {
+        let capacity = (*self.key_capacity.read())?;
+        Self::incoming_keys(messages)
+            .into_iter()
+            .find(|&(key, count)| {
+                shards
+                    .waiting(key)
+                    .map_or(0, VecDeque::len)
+                    .saturating_add(count)
+                    > capacity
+            })
+            .map(|(key, _)| key)
+    }
+
+    /// how many of `messages` have each key
+    fn incoming_keys(messages: &[InternalMessage<T>]) -> HashMap<&str, usize> {
+        let mut incoming = HashMap::new();
+        for key in messages.iter().flat_map(|message| message.keys.iter()) {
+            let count = incoming.entry(key.0.as_str()).or_insert(0_usize);
+            *count = count.saturating_add(1);
+        }
+        incoming
+    }
+
+    /// the oldest queued message which can be recieved now, or the oldest
+    /// queued message when all are blocked
+    fn oldest_queued(state: &State<T>) -> Option<u64> {
+        let mut ids = state.enqueue_times.values().map(|&(id, _)| id);
+        let first = ids.clone().next();
+        ids.find(|id| state.is_valid(*id)).or(first)
+    }
+
+    /// the oldest queued message sharing a key with `messages`, it is
+    /// the first one waiting for one of their keys
+    fn oldest_sharing_key(shards: &KeyGuards<'_>, messages: &[InternalMessage<T>]) -> Option<u64> {
+        messages
+            .iter()
+            .flat_map(|message| message.keys.iter())
+            .filter_map(|key| shards.waiting(&key.0)?.front().copied())
+            .min()
+    }
+
+    /// take a queued message out of the channel, the shards of its keys
+    /// are locked
+    fn drop_queued(
+        &self,
+        shards: &mut KeyGuards<'_>,
+        state: &mut State<T>,
+        message_id: u64,
+    ) -> Option<InternalMessage<T>> {
+        let mut message = state.id_to_message.remove(&message_id)?;
+        Self::unbind(&mut message);
+        if let Some(node) = state.id_to_node.remove(&message_id) {
+            let _ = state.list.remove(node);
+        }
+        let _ = state.enqueue_times.remove(&message.seq);
+        self.free_room(1);
+        if let Some(ref weigher) = self.weigher {
+            weigher.sub(&message.data);
+        }
+        // the messages waiting right behind it in its key queues
+        let mut next_ids = Vec::new();
+        for key in &message.keys {
+            let shard = shards.shard_mut(&key.0);
+            let is_active = shard.active.contains_key(&key.0);
+            let mut is_empty = false;
+            if let Some(queue) = shard.waiting.get_mut(&key.0) {
+                let was_first = queue.front() == Some(&message_id);
+                queue.retain(|id| *id != message_id);
+                if was_first && !is_active {
+                    next_ids.extend(queue.front().map(|id| (*id, key.0.as_str())));
+                }
+                is_empty = queue.is_empty();
+            }
+            if is_empty {
+                let _ = shard.waiting.remove(&key.0);
+            }
+        }
+        for (next_id, key) in next_ids {
+            self.unblock(state, next_id, key);
+        }
+        self.record_dropped(&message);
+        self.log_record(&Record::Drop(message_id));
+        Some(message)
+    }
+
+    /// count a dropped message and remember its id
+    fn record_dropped(&self, message: &InternalMessage<T>) {
+        debug!(id = message.id, keys:? = message.key_names(); "message dropped");
+        ChannelMetrics::add(&self.metrics.dropped, 1);
+        self.dropped_history.lock().push(message.id);
+    }
+
+    /// hand the dropped messages to the drop handler, the caller does
+    /// not hold `state`
+    fn report_dropped(&self, dropped: Vec<InternalMessage<T>>) {
+        if dropped.is_empty() {
+            return;
+        }
+        let handler = self.drop_handler.read().as_ref().map(Arc::clone);
+        if let Some(handler) = handler {
+            for message in dropped {
+                (handler.0)(message);
+            }
+        }
+    }
+
+    /// whether the channel is paused
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// pause or resume giving out messages, resuming wakes the reciever
+    pub(crate) fn set_paused(&self, paused: bool) {
+        let was_paused = self.change_and_wake(|_| self.paused.swap(paused, Ordering::Relaxed));
+        if was_paused != paused {
+            debug!(paused = paused; "channel pause changed");
+        }
+    }
+
+    /// lock `state`, the messages left in `inbox` are moved into the queue
+    /// first, so a key queue never names a message missing from `state`
+    pub(crate) fn lock_state(&self) -> MutexGuard<'_, State<T>> {
+        let mut state = self.state.lock();
+        for (seq, (message, since)) in self.inbox.drain() {
+            Self::insert_message(&mut state, message, seq, since);
+        }
+        state
+    }
+
+    /// wait for a send with `state` locked and no message queued. a
+    /// sender does not lock `state`, so it only does to wake the reciever
+    /// after it counts itself in `recievers_waiting`
+    pub(crate) fn wait_for_send(&self, state: &mut MutexGuard<'_, State<T>>) {
+        let _ = self.recievers_waiting.fetch_add(1, Ordering::SeqCst);
+        // a send filling `inbox` from now on sees the waiting reciever
+        if self.inbox.is_empty() {
+            self.cond_var_recieve.wait(state);
+        }
+        let _ = self.recievers_waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// wake a reciever waiting for a send, locking `state` makes sure it
+    /// is asleep already
+    fn wake_reciever(&self) {
+        if self.recievers_waiting.load(Ordering::SeqCst) > 0 {
+            drop(self.state.lock());
+            let _ = self.cond_var_recieve.notify_one();
+        }
+    }
+
+    /// change what waiting senders and recievers check, then wake them all.
+    /// the change is made with `state` locked, so a waiting sender or
+    /// reciever is either asleep already or sees it
+    fn change_and_wake<R>(&self, change: impl FnOnce(&mut State<T>) -> R) -> R {
+        let mut state = self.lock_state();
+        let changed = change(&mut state);
+        drop(state);
+        let _ = self.cond_var_send.notify_all();
+        let _ = self.cond_var_recieve.notify_all();
+        self.wake_watchers();
+        changed
+    }
+
+    /// reject `messages` sent to a closed channel
+    fn check_open(&self, messages: &[InternalMessage<T>]) -> Result<()> {
+        if self.is_closed() {
+            ChannelMetrics::add(&self.metrics.rejected, messages.len());
+            return Err(Errors::Closed);
+        }
+        Ok(())
+    }
+
+    /// whether the channel is closed
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+
+    /// close the channel, senders and recievers waiting on it are woken up
+    /// and the queued messages are kept. `take` takes them out as well, in
+    /// the order they were sent
+    pub(crate) fn close(&self, take: bool) -> Vec<InternalMessage<T>> {
+        let shards = take.then(|| self.keys.lock_all());
+        let (was_closed, taken) = self.change_and_wake(|state| {
+            let was_closed = self.closed.swap(true, Ordering::Relaxed);
+            let taken = match shards {
+                Some(mut shards) => self.take_queued(&mut shards, state),
+                None => Vec::new(),
+            };
+            (was_closed, taken)
+        });
+        if !was_closed {
+            debug!(taken = taken.len(); "channel closed");
+        }
+        taken
+    }
+
+    /// take every queued message out in the order they were pushed, so
+    /// each key keeps the order `recv` gives it out in. every shard is
+    /// locked, and the keys held by recieved messages stay active
+    fn take_queued(
+        &self,
+        shards: &mut KeyGuards<'_>,
+        state: &mut State<T>,
+    ) -> Vec<InternalMessage<T>> {
+        let mut taken: Vec<InternalMessage<T>> = state
+            .id_to_message
+            .drain()
+            .map(|(_, message)| message)
+            .collect();
+        // the order they were pushed in, which `recv` keeps for each key
+        taken.sort_unstable_by_key(|message| message.seq);
+        self.free_room(taken.len());
+        for message in &mut taken {
+            Self::unbind(message);
+            if let Some(ref weigher) = self.weigher {
+                weigher.sub(&message.data);
+            }
+            // they are not recovered after a crash, the caller owns them now
+            self.log_record(&Record::Drop(message.id));
+        }
+        for (_, node) in state.id_to_node.drain() {
+            let _ = state.list.remove(node);
+        }
+        // only queued messages wait in the key queues
+        shards.clear_waiting();
+        state.enqueue_times.clear();
+        taken
+    }
+
+    /// reject `messages` when they never fit: a batch larger than the
+    /// channel, heavier than the channel, or with more messages of one key
+    /// than its queue holds
+    fn check_capacity(&self, messages: &[InternalMessage<T>]) -> Result<()> {
+        let bounded_size = self.bounded_size();
+        let oversized = bounded_size != -1
+            && i32::try_from(messages.len()).map_or(true, |size| size > bounded_size);
+        let overweight = self.weigher.as_ref().is_some_and(|weigher| {
+            weigher.exceeds(weigher.weigh(messages.iter().map(|message| &message.data)))
+        });
+        let over_key_capacity = self.key_capacity.read().is_some_and(|capacity| {
+            Self::incoming_keys(messages)
+                .values()
+                .any(|count| *count > capacity)
+        });
+        if oversized || overweight || over_key_capacity {
+            ChannelMetrics::add(&self.metrics.rejected, messages.len());
+            return Err(Errors::ExceedCapacity);
+        }
+        Ok(())
+    }
+
+    /// the capacity of the channel, -1 means it is unbounded
+    pub(crate) fn bounded_size(&self) -> i32 {
+        self.bounded_size.load(Ordering::Relaxed)
+    }
+
+    /// change the capacity of the channel, a negative one makes it
+    /// unbounded. queued messages are kept when it shrinks, and the
+    /// senders are woken up to check the new bound
+    pub(crate) fn set_bounded_size(&self, bounded_size: i32) {
+        let bounded_size = bounded_size.max(-1);
+        let old = self.change_and_wake(|_| self.bounded_size.swap(bounded_size, Ordering::Relaxed));
+        debug!(old = old, new = bounded_size; "channel capacity changed");
+    }
+
+    /// wake the senders up once a bounded channel has more room,
+    /// a batch may wait for more than one free place
+    pub(crate) fn notify_senders(&self) {
+        if self.bounded_size() != -1 || self.weigher.is_some() || self.key_capacity.read().is_some()
+        {
+            let _ = self.cond_var_send.notify_all();
+        }
+    }
+
+    /// log the messages about to be pushed, the caller holds `state`
+    /// so the log has the order of the queue. the messages are written as one
+    /// record, so they are recovered all together or not at all
+    pub(crate) fn log_sends(&self, messages: &[InternalMessage<T>]) -> Result<()> {
+        if let Some(ref durability) = self.durability {
+            let logged = messages
+                .iter()
+                .map(|message| LoggedMessage {
+                    id: message.id,
+                    keys: message
+                        .keys
+                        .iter()
+                        .map(|key| String::from(&key.0))
+                        .collect(),
+                    payload: (durability.encode)(&message.data),
+                })
+                .collect();
+            if let Err(err) = durability.log.lock().append(&Record::Send(logged)) {
+                error!(error:% = err; "failed to log sent messages");
+                ChannelMetrics::add(&self.metrics.rejected, messages.len());
+                return Err(Errors::WriteAheadLogError);
+            }
+        }
+        Ok(())
+    }
+
+    /// log a delivery or a key release, the caller holds `state`.
+    /// a record which can not be written only means the message may be
+    /// delivered again after a restart
+    pub(crate) fn log_record(&self, record: &Record) {
+        if let Some(ref durability) = self.durability {
+            if let Err(err) = durability.log.lock().append(record) {
+                error!(error:% = err; "failed to log {record:?}");
+            }
+        }
+    }
+
+    /// pick the next message to give out. the oldest valid message waiting
+    /// longer than `aging_threshold` goes first, otherwise the first
+    /// message of the list
+    pub(crate) fn next_message(&self, state: &State<T>) -> u64 {
+        let threshold = *self.aging_threshold.read();
+        let now = Instant::now();
+        for &(id, since) in state.enqueue_times.values().take(AGING_SCAN_LIMIT) {
+            if now.saturating_duration_since(since) < threshold {
+                break;
+            }
+            if state.is_valid(id) {
+                return id;
+            }
+        }
+        state.list.list_first()
+    }
+
+    /// pick up to `max` valid messages to give out together, in the order
+    /// `next_message` picks them
+    pub(crate) fn next_messages(&self, state: &State<T>, max: usize) -> Vec<u64> {
+        let threshold = *self.aging_threshold.read();
+        let now = Instant::now();
+        let mut message_ids: Vec<u64> = state
+            .enqueue_times
+            .values()
+            .take(AGING_SCAN_LIMIT)
+            .take_while(|&&(_, since)| now.saturating_duration_since(since) >= threshold)
+            .map(|&(id, _)| id)
+            .filter(|id| state.is_valid(*id))
+            .take(max)
+            .collect();
+        let aged: HashSet<u64> = message_ids.iter().copied().collect();
+        // valid messages are always in front of the list
+        let rest = max.saturating_sub(message_ids.len());
+        message_ids.extend(
+            state
+                .list
+                .ids()
+                .take_while(|id| state.is_valid(*id))
+                .filter(|id| !aged.contains(id))
+                .take(rest),
+        );
+        message_ids
+    }
+
+    /// give out the valid messages `message_ids` picked with `picked`
+    /// locked. the shards of their keys are locked before `state`, so
+    /// unless they are free at once it is unlocked meanwhile, and the
+    /// messages dropped by then are left out
+    pub(crate) fn give_out(
+        &self,
+        mut picked: MutexGuard<'_, State<T>>,
+        message_ids: &[u64],
+    ) -> Vec<InternalMessage<T>> {
+        let shard_ids = self.keys.shards_of(picked.keys_of(message_ids));
+        if let Some(mut shards) = self.keys.try_lock_shards(&shard_ids) {
+            return message_ids
+                .iter()
+                .map(|id| self.remove(&mut shards, &mut picked, *id))
+                .collect();
+        }
+        drop(picked);
+        let mut shards = self.keys.lock_shards(&shard_ids);
+        let mut state = self.lock_state();
+        // a valid message stays valid until it leaves the queue
+        let valid: Vec<u64> = message_ids
+            .iter()
+            .copied()
+            .filter(|id| state.is_valid(*id))
+            .collect();
+        valid
+            .into_iter()
+            .map(|id| self.remove(&mut shards, &mut state, id))
+            .collect()
+    }
+
+    /// check whether there is a valid message now, valid messages
+    /// are always in front of the list
+    pub(crate) fn has_valid_message(&self) -> bool {
+        let state = self.lock_state();
+        if state.queued() == 0 {
+            return self.is_closed();
+        }
+        !self.is_paused() && state.is_valid(state.list.list_first())
+    }
+
+    /// call `handler` with every key released by a recieved message
+    #[cfg(target_os = "linux")]
+    pub(crate) fn set_release_handler<F>(&self, handler: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        *self.release_handler.write() = Some(ReleaseHandler(Box::new(handler)));
+    }
+
+    /// how many entries every map of the channel keeps, and how many
+    /// slots the queue holds, used or free
+    #[cfg(test)]
+    pub(crate) fn retained(&self) -> Vec<(&'static str, usize)> {
+        let shards = self.keys.lock_all();
+        let state = self.lock_state();
+        vec![
+            ("list slots", state.list.slots()),
+            ("id_to_node", state.id_to_node.len()),
+            ("id_to_message", state.id_to_message.len()),
+            ("active_ids", state.active_ids.len()),
+            ("enqueue_times", state.enqueue_times.len()),
+            (
+                "waiting",
+                shards.shards().map(|shard| shard.waiting.len()).sum(),
+            ),
+            (
+                "active",
+                shards.shards().map(|shard| shard.active.len()).sum(),
+            ),
+        ]
+    }
+
+    /// wake `signal` up whenever a message becomes valid
+    pub(crate) fn watch(&self, signal: &Arc<Signal>) {
+        self.watchers.write().push(Arc::downgrade(signal));
+    }
+
+    /// stop waking `signal` up
+    pub(crate) fn unwatch(&self, signal: &Arc<Signal>) {
+        self.watchers
+            .write()
+            .retain(|watcher| !std::ptr::eq(watcher.as_ptr(), Arc::as_ptr(signal)));
+    }
+
+    /// tell the watching `Select`s that a message became valid
+    pub(crate) fn wake_watchers(&self) {
+        let watchers = self.watchers.read();
+        for watcher in watchers.iter() {
+            if let Some(signal) = watcher.upgrade() {
+                signal.notify();
+            }
+        }
+    }
+
+    /// get how long messages wait in the channel
+    pub(crate) fn wait_stats(&self) -> WaitStats {
+        self.wait_stats_of(&self.lock_state())
+    }
+
+    /// how long messages wait, `state` is locked by the caller
+    fn wait_stats_of(&self, state: &State<T>) -> WaitStats {
+        let longest_queued_wait = state
+            .enqueue_times
+            .values()
+            .next()
+            .map_or(Duration::ZERO, |&(_, since)| since.elapsed());
+        WaitStats {
+            longest_queued_wait,
+            longest_delivered_wait: self.metrics.delivery_latency.max(),
+        }
+    }
+
+    /// take a snapshot of the metrics, counting the queued messages
+    /// only happens here so sending and recieving stay cheap. every field
+    /// is read with the keys and `state` locked, so they agree
+    pub(crate) fn stats(&self) -> ChannelStats {
+        let shards = self.keys.lock_all();
+        let state = self.lock_state();
+        let active_keys = shards.shards().map(|shard| shard.active.len()).sum();
+        let queued = usize::try_from(state.queued()).unwrap_or(0);
+        let eligible = state
+            .id_to_message
+            .values()
+            .filter(|message| message.ref_count == 0)
+            .count();
+        ChannelStats {
+            queued,
+            eligible,
+            blocked: queued.saturating_sub(eligible),
+            active_keys,
+            sent: self.metrics.sent.load(Ordering::Relaxed),
+            recieved: self.metrics.recieved.load(Ordering::Relaxed),
+            rejected: self.metrics.rejected.load(Ordering::Relaxed),
+            dropped: self.metrics.dropped.load(Ordering::Relaxed),
+            weight: self.weigher.as_ref().map_or(0, Weigher::current),
+            wait: self.wait_stats_of(&state),
+            delivery_latency: self.metrics.delivery_latency.snapshot(),
+            hold_time: self.metrics.hold_time.snapshot(),
+        }
+    }
+
+    /// take a consistent view of the queued messages and active keys
+    pub(crate) fn snapshot(&self) -> ChannelSnapshot {
+        let shards = self.keys.lock_all();
+        let state = self.lock_state();
+        // aged valid messages go first, see `next_message`
+        let threshold = *self.aging_threshold.read();
+        let now = Instant::now();
+        let mut order: Vec<u64> = state
+            .enqueue_times
+            .values()
+            .take(AGING_SCAN_LIMIT)
+            .take_while(|&&(_, since)| now.saturating_duration_since(since) >= threshold)
+            .map(|&(id, _)| id)
+            .filter(|id| state.is_valid(*id))
+            .collect();
+        let aged: HashSet<u64> = order.iter().copied().collect();
+        order.extend(
+            state
+                .list
+                .list_ids()
+                .into_iter()
+                .filter(|id| !aged.contains(id)),
+        );
+        let queued = order
+            .into_iter()
+            .filter_map(|id| {
+                let message = state.id_to_message.get(&id)?;
+                let keys: Vec<String> = message
+                    .keys
+                    .iter()
+                    .map(|key| String::from(&key.0))
+                    .collect();
+                let blocked_by_keys = keys
+                    .iter()
+                    .filter(|key| shards.is_active(key))
+                    .map(String::from)
+                    .collect();
+                // the message right before it in each key queue
+                let waiting_behind = keys
+                    .iter()
+                    .filter_map(|key| {
+                        let queue = shards.waiting(key)?;
+                        let position = queue.iter().position(|queued| *queued == id)?;
+                        queue.get(position.checked_sub(1)?).copied()
+                    })
+                    .collect();
+                Some(QueuedMessage {
+                    id,
+                    keys,
+                    eligible: message.ref_count == 0,
+                    blocked_by_keys,
+                    waiting_behind,
+                })
+            })
+            .collect();
+        let mut active_keys: Vec<ActiveKey> = shards
+            .shards()
+            .flat_map(|shard| shard.active.iter())
+            .map(|(key, holder)| ActiveKey {
+                key: String::from(key),
+                holder: *holder,
+            })
+            .collect();
+        active_keys.sort_by(|left, right| left.key.cmp(&right.key));
+        ChannelSnapshot {
+            queued,
+            active_keys,
+        }
+    }
+
+    /// get the lifecycle status of a message
+    pub(crate) fn status(&self, message_id: u64) -> MessageStatus {
+        let shards = self.keys.lock_all();
+        let state = self.lock_state();
+        if let Some(message) = state.id_to_message.get(&message_id) {
+            if message.ref_count == 0 {
+                return MessageStatus::Queued;
+            }
+            let blocking_keys = message
+                .keys
+                .iter()
+                .filter(|key| {
+                    shards.is_active(&key.0)
+                        || shards.waiting(&key.0).and_then(VecDeque::front) != Some(&message_id)
+                })
+                .map(|key| String::from(&key.0))
+                .collect();
+            return MessageStatus::Blocked(blocking_keys);
+        }
+        if state.active_ids.contains_key(&message_id) {
+            return MessageStatus::Active;
+        }
+        if self.completed_history.lock().contains(message_id) {
+            return MessageStatus::Completed;
+        }
+        if self.dropped_history.lock().contains(message_id) {
+            return MessageStatus::Dropped;
+        }
+        MessageStatus::Unknown
+    }
+
+    /// `key` of a waiting message does not block it any more,
+    /// move the message to the front once nothing blocks it
+    pub(crate) fn unblock(&self, state: &mut State<T>, message_id: u64, key: &str) {
+        if let Some(message) = state.id_to_message.get_mut(&message_id) {
+            // the key blocked it since it was pushed
+            if let Some(&(_, since)) = state.enqueue_times.get(&message.seq) {
+                self.hot_keys
+                    .lock()
+                    .record_blocked_time(key, since.elapsed());
+            }
+            message.ref_count = message.ref_count.wrapping_sub(1);
+            if message.ref_count == 0 {
+                if let Some(node) = state.id_to_node.get(&message_id) {
+                    // no_duplicate_key will be first
+                    state.list.move_to_front(*node);
+                }
+                trace!(id = message_id; "message unblocked");
+                self.wake_watchers();
+            }
+        }
+    }
+
+    /// remove a message to give it out, the shards of its keys are locked
+    pub(crate) fn remove(
+        &self,
+        shards: &mut KeyGuards<'_>,
+        state: &mut State<T>,
+        message_id: u64,
+    ) -> InternalMessage<T> {
+        let res = state.id_to_message.remove(&message_id);
+        // remove message_id in list
+        if let Some(node) = state.id_to_node.remove(&message_id) {
+            let _ = state.list.remove(node);
+        }
+        if let Some(message_0) = res {
+            ChannelMetrics::add(&self.metrics.recieved, 1);
+            self.free_room(1);
+            if let Some(ref weigher) = self.weigher {
+                weigher.sub(&message_0.data);
+            }
+            self.log_record(&Record::Deliver(message_id));
+            if let Some((_, since)) = state.enqueue_times.remove(&message_0.seq) {
+                let waited = since.elapsed();
+                self.metrics.delivery_latency.record(waited);
+                debug!(
+                    id = message_id,
+                    keys:? = message_0.key_names(),
+                    waited_us = u64::try_from(waited.as_micros()).unwrap_or(u64::MAX);
+                    "message recieved"
+                );
+            }
+            // record the keys held by the recieved message
+            let held_keys: HashSet<String> = message_0
+                .keys
+                .iter()
+                .map(|key| String::from(&key.0))
+                .collect();
+            if held_keys.is_empty() {
+                self.completed_history.lock().push(message_id);
+            } else {
+                for key in &held_keys {
+                    let _ = shards
+                        .shard_mut(key)
+                        .active
+                        .insert(String::from(key), message_id);
+                }
+                let active = ActiveMessage {
+                    keys: held_keys,
+                    since: Instant::now(),
+                };
+                let _ = state.active_ids.insert(message_id, active);
+            }
+            for key_ in &message_0.keys {
+                // a valid message is the first one waiting for each of its
+                // keys, the next one is now blocked by the active key instead
+                let shard = shards.shard_mut(&key_.0);
+                let mut is_empty = false;
+                if let Some(queue) = shard.waiting.get_mut(&key_.0) {
+                    let front = queue.pop_front();
+                    debug_assert_eq!(front, Some(message_id));
+                    is_empty = queue.is_empty();
+                }
+                if is_empty {
+                    let _ = shard.waiting.remove(&key_.0);
+                }
             }
             return message_0;
         }
@@ -261,17 +1558,123 @@ impl<T> MspcChannel<T> {
 
     /// `channel` func is used to get sender and reciever
     pub(crate) fn channel(bounded_size_: i32) -> (Sender<T>, Reciever<T>) {
+        Self::with_options(
+            bounded_size_,
+            OverflowPolicy::Block,
+            None,
+            None,
+            DEFAULT_SHARDS,
+        )
+    }
+
+    /// `sharded_channel` is used to get sender and reciever of a channel
+    /// splitting its keys into `shards` shards, one shard keeps every key
+    /// behind the same lock
+    pub(crate) fn sharded_channel(bounded_size_: i32, shards: usize) -> (Sender<T>, Reciever<T>) {
+        Self::with_options(bounded_size_, OverflowPolicy::Block, None, None, shards)
+    }
+
+    /// `weighted_channel` is used to get sender and reciever of a channel
+    /// bounded by the total weight of its queued messages instead of their
+    /// number, `weigh` gives the weight of one message
+    pub(crate) fn weighted_channel<F>(max_weight: usize, weigh: F) -> (Sender<T>, Reciever<T>)
+    where
+        F: Fn(&T) -> usize + Send + Sync + 'static,
+    {
+        let weigher = Weigher::new(max_weight, weigh);
+        Self::with_options(
+            -1,
+            OverflowPolicy::Block,
+            Some(weigher),
+            None,
+            DEFAULT_SHARDS,
+        )
+    }
+
+    /// `channel_with_policy` is used to get sender and reciever of a channel
+    /// handling a full bound with `overflow_policy`
+    pub(crate) fn channel_with_policy(
+        bounded_size_: i32,
+        overflow_policy: OverflowPolicy,
+    ) -> (Sender<T>, Reciever<T>) {
+        Self::with_options(bounded_size_, overflow_policy, None, None, DEFAULT_SHARDS)
+    }
+
+    /// `durable_channel` creates a channel logging to the file at `path`.
+    /// the messages left in the log by a previous run are queued again in
+    /// their order, including the recieved ones which did not release all
+    /// their keys, so every message is delivered at least once
+    pub(crate) fn durable_channel(
+        path: &Path,
+        bounded_size_: i32,
+    ) -> io::Result<(Sender<T>, Reciever<T>)>
+    where
+        T: Persist,
+    {
+        let (log, recovered) = WriteAheadLog::open(path)?;
+        let durability = Durability {
+            log: Mutex::new(log),
+            encode: T::to_bytes,
+        };
+        let (sender, reciever) = Self::with_options(
+            bounded_size_,
+            OverflowPolicy::Block,
+            None,
+            Some(durability),
+            DEFAULT_SHARDS,
+        );
+        let chan = &sender.chan;
+        chan.message_id.store(recovered.last_id, Ordering::Relaxed);
+        let mut shards = chan.keys.lock_all();
+        for logged in recovered.messages {
+            let data = T::from_bytes(&logged.payload).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "message data can not be decoded",
+                )
+            })?;
+            let mut message = InternalMessage::new(logged.keys, data);
+            chan.bind(&mut message, logged.id);
+            chan.push_message(&mut shards, message);
+        }
+        drop(shards);
+        let queued = chan.lock_state().queued();
+        debug!(path:? = path, queued = queued; "channel recovered");
+        Ok((sender, reciever))
+    }
+
+    /// create a channel, it is durable when `durability` is given
+    fn with_options(
+        bounded_size_: i32,
+        overflow_policy: OverflowPolicy,
+        weigher: Option<Weigher<T>>,
+        durability: Option<Durability<T>>,
+        shards: usize,
+    ) -> (Sender<T>, Reciever<T>) {
         let message_channel = Arc::new(MspcChannel {
-            cached_messages: Arc::new(Mutex::new(List::new())),
-            counter: Arc::new(RwLock::new(HashSet::<String>::new())),
-            bounded_size: bounded_size_,
+            keys: KeyTable::new(shards),
+            state: Mutex::new(State::new()),
+            inbox: Inbox::new(shards),
+            queued: AtomicUsize::new(0),
+            recievers_waiting: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            paused: AtomicBool::new(false),
+            bounded_size: AtomicI32::new(bounded_size_),
+            weigher,
+            key_capacity: RwLock::new(None),
+            overflow_policy,
+            drop_handler: RwLock::new(None),
+            release_handler: RwLock::new(None),
             cond_var_recieve: Arc::new(Condvar::new()),
             cond_var_send: Arc::new(Condvar::new()),
-            message_id: Mutex::new(0),
-            id_to_node: CellMap(RefCell::new(HashMap::new())),
-            key_message_id_in_duplicate: CellMap(RefCell::new(HashMap::new())),
-            id_to_message: CellMap(RefCell::new(HashMap::new())),
-            key_to_message_id: CellMap(RefCell::new(HashMap::new())),
+            message_id: AtomicU64::new(0),
+            completed_history: Mutex::new(CompletedHistory::new(COMPLETED_HISTORY_SIZE)),
+            dropped_history: Mutex::new(CompletedHistory::new(COMPLETED_HISTORY_SIZE)),
+            aging_threshold: RwLock::new(DEFAULT_AGING_THRESHOLD),
+            metrics: ChannelMetrics::new(),
+            hot_keys: Mutex::new(HotKeySketch::new(HOT_KEY_CAPACITY)),
+            watchers: RwLock::new(Vec::new()),
+            durability,
         });
         let sender = Sender {
             chan: Arc::clone(&message_channel),
@@ -281,45 +1684,119 @@ impl<T> MspcChannel<T> {
         };
         (sender, reciever)
     }
-    /// push message in channel
-    pub(crate) fn push_message(&self, message: InternalMessage<T>) {
-        let mut write_guard_0 = self.cached_messages.lock();
-        let mut flag = false;
-        {
-            let read_guard = self.counter.read();
-            let mut write_guard = self.key_message_id_in_duplicate.0.borrow_mut();
-            let mut wirte_message = message.ref_count.write();
-            let mut write_message2 = self.key_to_message_id.0.borrow_mut();
-            for key_ in &message.keys {
-                if write_message2.get(&key_.0).is_none() {
-                    let _ = write_message2.insert(String::from(&key_.0), HashSet::new());
-                }
-                if let Some(set) = write_message2.get_mut(&key_.0) {
-                    let _ = set.insert(message.id);
-                }
-                let res = read_guard.get(&key_.0);
-                if res.is_some() {
-                    if write_guard.get(&key_.0).is_none() {
-                        let _ = write_guard.insert(String::from(&key_.0), HashSet::new());
-                    }
-                    if let Some(set) = write_guard.get_mut(&key_.0) {
-                        let _ = set.insert(message.id);
-                    }
-                    *wirte_message = wirte_message.wrapping_add(1);
-                    flag = true;
-                }
+    /// push a recovered message in channel over the bound, the shards of
+    /// its keys are locked
+    pub(crate) fn push_message(&self, shards: &mut KeyGuards<'_>, message: InternalMessage<T>) {
+        let blocked = Self::blocked_keys(shards, std::slice::from_ref(&message));
+        self.take_room(1);
+        self.stage(shards, std::iter::once(message), blocked);
+    }
+
+    /// how many keys of each of `messages` are blocked, by a recieved
+    /// message or by an earlier message with the key. only the shards of
+    /// their keys are read
+    fn blocked_keys(shards: &KeyGuards<'_>, messages: &[InternalMessage<T>]) -> Vec<i32> {
+        let mut sent = HashSet::new();
+        messages
+            .iter()
+            .map(|message| {
+                let blocked = message.keys.iter().filter(|key| {
+                    let first = sent.insert(key.0.as_str());
+                    !first
+                        || shards.is_active(&key.0)
+                        || shards
+                            .waiting(&key.0)
+                            .is_some_and(|queue| !queue.is_empty())
+                });
+                i32::try_from(blocked.count()).unwrap_or(i32::MAX)
+            })
+            .collect()
+    }
+
+    /// leave sent messages in `inbox` for the queue, their room is taken.
+    /// the shards of their keys are locked, so the messages wait for
+    /// their keys at once and are moved into the queue before a holder
+    /// of `state` sees them there
+    fn stage<I>(&self, shards: &mut KeyGuards<'_>, messages: I, blocked: Vec<i32>)
+    where
+        I: IntoIterator<Item = InternalMessage<T>>,
+    {
+        let mut staged = Vec::new();
+        let mut waiting = Vec::new();
+        for (mut message, blocked_keys) in messages.into_iter().zip(blocked) {
+            message.ref_count = blocked_keys;
+            // a weighted channel always sends with `state` locked
+            if let Some(ref weigher) = self.weigher {
+                weigher.add(&message.data);
             }
+            let keys: Vec<String> = message
+                .keys
+                .iter()
+                .map(|key| String::from(&key.0))
+                .collect();
+            waiting.push((message.id, keys, blocked_keys));
+            staged.push((message, Instant::now()));
         }
-        let item = ListNode::create_node(message.id);
-        let mut write_guard2 = self.id_to_node.0.borrow_mut();
-        let _ = write_guard2.insert(message.id, Arc::<Cell>::clone(&item));
-        if flag {
-            write_guard_0.list_push_back(&item);
+        // the box of a locked shard is free of other senders of the shard
+        let index = shards
+            .first_shard()
+            .or_else(|| {
+                staged
+                    .first()
+                    .and_then(|first| usize::try_from(first.0.id).ok())
+            })
+            .unwrap_or(0);
+        self.inbox.push(index, staged);
+        let mut unblocked = false;
+        for (id, keys, blocked_keys) in waiting {
+            unblocked |= blocked_keys == 0;
+            self.wait_keys(shards, id, keys, blocked_keys);
+        }
+        if unblocked {
+            self.wake_watchers();
+        }
+    }
+
+    /// put a staged message in the queue at `seq`, `state` is locked. a
+    /// blocked message waits at the back, the others go first
+    fn insert_message(
+        state: &mut State<T>,
+        mut message: InternalMessage<T>,
+        seq: u64,
+        since: Instant,
+    ) {
+        let node = if message.ref_count > 0 {
+            state.list.list_push_back(message.id)
         } else {
-            write_guard_0.list_push_first(&item);
+            state.list.list_push_first(message.id)
+        };
+        let _ = state.id_to_node.insert(message.id, node);
+        message.seq = seq;
+        let _ = state.enqueue_times.insert(seq, (message.id, since));
+        let _ = state.id_to_message.insert(message.id, message);
+    }
+
+    /// add a queued message to the queues of its keys, the shards of
+    /// its keys are still locked so no reciever takes the keys meanwhile
+    fn wait_keys(&self, shards: &mut KeyGuards<'_>, id: u64, keys: Vec<String>, blocked_keys: i32) {
+        debug!(id = id, keys:? = keys; "message sent");
+        if blocked_keys > 0 {
+            debug!(id = id, blocked_keys = blocked_keys; "message enqueued as blocked");
+        }
+        for key in keys {
+            let shard = shards.shard_mut(&key);
+            // an active key or an earlier message with the key blocks it
+            if shard.active.contains_key(&key)
+                || shard
+                    .waiting
+                    .get(&key)
+                    .is_some_and(|queue| !queue.is_empty())
+            {
+                self.hot_keys.lock().record_blocked(&key);
+            }
+            shard.waiting.entry(key).or_default().push_back(id);
         }
-        let mut write_guard_id_to_message = self.id_to_message.0.borrow_mut();
-        let _ = write_guard_id_to_message.insert(message.id, message);
+        ChannelMetrics::add(&self.metrics.sent, 1);
     }
 }
 
@@ -336,4 +1813,70 @@ mod test {
         let res = reciever.recv();
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn test_take_in_queue_order() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        // the lower id is given first, but its send enqueues it last
+        let late_id = sender.chan.next_message_id();
+        assert!(sender
+            .send(InternalMessage::new(vec![String::from("a")], 1))
+            .is_ok());
+        let mut late = InternalMessage::new(vec![String::from("a")], 0);
+        sender.chan.bind(&mut late, late_id);
+        assert!(sender.chan.enqueue(vec![late]).is_ok());
+        let data: Vec<i32> = reciever
+            .close_and_take()
+            .iter()
+            .map(|message| message.data)
+            .collect();
+        assert_eq!(data, vec![1, 0]);
+    }
+
+    #[test]
+    fn test_aging_in_push_order() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        // the lower id is pushed last, so it is the newer message
+        let late_id = sender.chan.next_message_id();
+        assert!(sender
+            .send(InternalMessage::new(vec![String::from("a")], 1))
+            .is_ok());
+        let mut late = InternalMessage::new(vec![String::from("b")], 0);
+        sender.chan.bind(&mut late, late_id);
+        assert!(sender.chan.enqueue(vec![late]).is_ok());
+        reciever.set_aging_threshold(Duration::ZERO);
+        let data: Vec<i32> = (0..2)
+            .filter_map(|_| reciever.recv().ok())
+            .map(|message| message.data)
+            .collect();
+        assert_eq!(data, vec![1, 0]);
+    }
+
+    #[test]
+    fn test_send_without_state() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(2);
+        let state = reciever.chan.state.lock();
+        let (done, finished) = std::sync::mpsc::channel();
+        let handle = std::thread::spawn(move || {
+            let sent = sender.send(InternalMessage::new(vec![String::from("a")], 1));
+            assert!(done.send(sent.is_ok()).is_ok());
+        });
+        // the send returns while `state` stays locked
+        assert_eq!(finished.recv_timeout(Duration::from_secs(10)), Ok(true));
+        drop(state);
+        assert!(handle.join().is_ok());
+        assert_eq!(reciever.recv().map(|message| message.data), Ok(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "message ids are exhausted")]
+    fn test_message_id_never_wraps() {
+        let (sender, _reciever) = MspcChannel::<i32>::channel(-1);
+        sender
+            .chan
+            .message_id
+            .store(u64::MAX.wrapping_sub(1), Ordering::Relaxed);
+        assert_eq!(sender.chan.next_message_id(), u64::MAX);
+        let _ = sender.chan.next_message_id();
+    }
 }
@@ -0,0 +1,33 @@
+use std::fmt::{self, Debug};
+
+use super::mspc_channel::InternalMessage;
+
+/// `OverflowPolicy` tells what a bounded channel does with a message
+/// sent while it is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum OverflowPolicy {
+    /// the sender waits until there is room
+    #[default]
+    Block,
+    /// the message is refused with `ExceedCapacity`
+    Reject,
+    /// the oldest queued message which can be recieved now is dropped,
+    /// or the oldest queued message when all are blocked
+    DropOldest,
+    /// the new message is dropped, `send` still returns its id
+    DropNewest,
+    /// the oldest queued message sharing a key with the new one is
+    /// dropped, or the new message when none shares a key, so the
+    /// messages of other keys are never dropped for it
+    DropOldestSameKey,
+}
+
+/// `DropHandler` is told about every message dropped by the overflow policy
+pub(crate) struct DropHandler<T>(pub(crate) Box<dyn Fn(InternalMessage<T>) + Send + Sync>);
+
+impl<T> Debug for DropHandler<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DropHandler")
+    }
+}
@@ -0,0 +1,148 @@
+use super::mspc_channel::Reciever;
+use parking_lot::{Condvar, Mutex};
+use std::{
+    fmt::{self, Debug, Formatter},
+    sync::Arc,
+};
+
+/// `Signal` is shared by a `Select` and the channels it watches, a channel
+/// bumps the generation whenever a message becomes deliverable
+#[derive(Debug, Default)]
+pub(crate) struct Signal {
+    /// bumped on every notification
+    generation: Mutex<u64>,
+    /// waiters sleep here until the generation changes
+    cond_var: Condvar,
+}
+
+impl Signal {
+    /// get the current generation
+    pub(crate) fn generation(&self) -> u64 {
+        *self.generation.lock()
+    }
+
+    /// tell the waiters that something may be deliverable
+    pub(crate) fn notify(&self) {
+        let mut generation = self.generation.lock();
+        *generation = generation.wrapping_add(1);
+        let _ = self.cond_var.notify_all();
+    }
+
+    /// block until the generation is not `seen` any more
+    pub(crate) fn wait_changed(&self, seen: u64) {
+        let mut generation = self.generation.lock();
+        while *generation == seen {
+            self.cond_var.wait(&mut generation);
+        }
+    }
+}
+
+/// `Selectable` is a reciever which can be watched by a `Select`,
+/// it hides the message type of the reciever
+pub(crate) trait Selectable {
+    /// check whether `recv` would give out a message now, or fail at once
+    /// because the channel is closed and empty
+    fn has_valid_message(&self) -> bool;
+    /// wake `signal` up whenever a message becomes deliverable
+    fn watch(&self, signal: &Arc<Signal>);
+    /// stop waking `signal` up
+    fn unwatch(&self, signal: &Arc<Signal>);
+}
+
+impl<T> Selectable for Reciever<T> {
+    fn has_valid_message(&self) -> bool {
+        self.chan().has_valid_message()
+    }
+
+    fn watch(&self, signal: &Arc<Signal>) {
+        self.chan().watch(signal);
+    }
+
+    fn unwatch(&self, signal: &Arc<Signal>) {
+        self.chan().unwatch(signal);
+    }
+}
+
+/// `Select` waits on many recievers, maybe of different message types,
+/// until one of them has a deliverable message. it sleeps on a signal
+/// shared with the channels instead of polling them
+pub struct Select<'a> {
+    /// the watched recievers, in registration order
+    recievers: Vec<&'a dyn Selectable>,
+    /// wakes us up when any watched channel has a deliverable message
+    signal: Arc<Signal>,
+}
+
+impl<'a> Select<'a> {
+    /// `new` creates a `Select` watching nothing
+    #[inline]
+    #[must_use]
+    pub fn new() -> Select<'a> {
+        Select {
+            recievers: Vec::new(),
+            signal: Arc::new(Signal::default()),
+        }
+    }
+
+    /// `recv` registers a reciever, its index is the number of recievers
+    /// registered before it
+    #[inline]
+    #[must_use]
+    pub fn recv<T>(mut self, reciever: &'a Reciever<T>) -> Select<'a> {
+        reciever.watch(&self.signal);
+        self.recievers.push(reciever);
+        self
+    }
+
+    /// `try_ready` returns the index of a reciever which has a deliverable
+    /// message now or is closed and empty, without blocking
+    #[inline]
+    #[must_use]
+    pub fn try_ready(&self) -> Option<usize> {
+        self.recievers
+            .iter()
+            .position(|reciever| reciever.has_valid_message())
+    }
+
+    /// `ready` blocks until a registered reciever has a deliverable message
+    /// or is closed and empty, and returns its index. `recv` on that
+    /// reciever returns at once then
+    #[inline]
+    #[must_use]
+    pub fn ready(&self) -> usize {
+        loop {
+            // read the generation first, so a message arriving while we
+            // check the recievers changes it and we don't sleep
+            let seen = self.signal.generation();
+            if let Some(index) = self.try_ready() {
+                return index;
+            }
+            self.signal.wait_changed(seen);
+        }
+    }
+}
+
+impl Default for Select<'_> {
+    #[inline]
+    fn default() -> Self {
+        Select::new()
+    }
+}
+
+impl Drop for Select<'_> {
+    #[inline]
+    fn drop(&mut self) {
+        for reciever in &self.recievers {
+            reciever.unwatch(&self.signal);
+        }
+    }
+}
+
+impl Debug for Select<'_> {
+    #[inline]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Select")
+            .field("recievers", &self.recievers.len())
+            .finish_non_exhaustive()
+    }
+}
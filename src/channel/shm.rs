@@ -0,0 +1,715 @@
+use crate::errors::{Errors, Result};
+use log::{debug, error};
+use parking_lot::Mutex;
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io,
+    marker::PhantomData,
+    os::unix::io::AsRawFd,
+    path::Path,
+    ptr,
+    sync::{
+        atomic::{fence, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use super::bridge::{decode_message, encode_body};
+use super::mspc_channel::{InternalMessage, MspcChannel, Reciever, Sender};
+use super::wal::Persist;
+
+/// marks an initialized mapping, `keyed_sh`
+const SHM_MAGIC: u64 = 0x6B65_7965_645F_7368;
+/// the header takes the first bytes of the mapping
+const HEADER_SIZE: usize = 128;
+/// every slot starts with its sequence and the length of its message
+const SLOT_HEADER_SIZE: usize = 16;
+/// slots are aligned for their sequence
+const SLOT_ALIGN: usize = 8;
+/// the max length of a key in the key table
+pub(crate) const KEY_SIZE: usize = 48;
+/// the words holding the bytes of a key
+const KEY_WORDS: usize = KEY_SIZE / 8;
+/// the key table has this many entries for every slot of the ring
+const KEY_ENTRIES_PER_SLOT: u64 = 4;
+
+/// `Header` is shared by all processes, it only has atomics because
+/// every process reads and writes it at the same time
+#[repr(C)]
+#[derive(Debug)]
+struct Header {
+    /// `SHM_MAGIC` once the mapping is initialized
+    magic: AtomicU64,
+    /// how many slots the ring has
+    slots: AtomicU64,
+    /// the max size of a message in a slot
+    slot_size: AtomicU64,
+    /// the position the next producer writes to
+    enqueue_pos: AtomicU64,
+    /// the position the consumer reads next
+    dequeue_pos: AtomicU64,
+    /// futex word bumped after a message is written
+    not_empty: AtomicU32,
+    /// futex word bumped after a slot is freed
+    not_full: AtomicU32,
+    /// the consumer sleeps on `not_empty`
+    consumer_waiting: AtomicU32,
+    /// how many producers sleep on `not_full`
+    producers_waiting: AtomicU32,
+}
+
+const _: () = assert!(size_of::<Header>() <= HEADER_SIZE);
+
+/// `KeyEntry` is an entry of the key table, which follows the header.
+/// only the consumer writes it, any process may read it
+#[repr(C)]
+#[derive(Debug)]
+struct KeyEntry {
+    /// the token of the recieved message holding the key, 0 when the
+    /// entry is free. a token is never used twice, so a reader seeing the
+    /// same token before and after reading the key has read it whole
+    holder: AtomicU64,
+    /// the length of the key
+    len: AtomicU32,
+    /// keeps the key aligned
+    padding: AtomicU32,
+    /// the key bytes, little endian and zero padded
+    words: [AtomicU64; KEY_WORDS],
+}
+
+const _: () = assert!(size_of::<KeyEntry>() == 64);
+
+impl KeyEntry {
+    /// the words of `key`, which is at most `KEY_SIZE` bytes
+    fn words_of(key: &str) -> [u64; KEY_WORDS] {
+        let mut words = [0_u64; KEY_WORDS];
+        for (word, chunk) in words.iter_mut().zip(key.as_bytes().chunks(8)) {
+            let mut bytes = [0_u8; 8];
+            for (byte, value) in bytes.iter_mut().zip(chunk) {
+                *byte = *value;
+            }
+            *word = u64::from_le_bytes(bytes);
+        }
+        words
+    }
+
+    /// let the message with `holder` hold `key`, the entry is free
+    fn hold(&self, key: &str, holder: u64) {
+        self.len
+            .store(u32::try_from(key.len()).unwrap_or(0), Ordering::Relaxed);
+        for (word, value) in self.words.iter().zip(Self::words_of(key)) {
+            word.store(value, Ordering::Relaxed);
+        }
+        self.holder.store(holder, Ordering::Release);
+    }
+
+    /// free the entry, the key may be overwritten after it
+    fn release(&self) {
+        self.holder.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+    }
+
+    /// whether a recieved message holds `key` in this entry
+    fn is_held(&self, key: &str) -> bool {
+        let holder = self.holder.load(Ordering::Acquire);
+        if holder == 0 {
+            return false;
+        }
+        let same_len = usize::try_from(self.len.load(Ordering::Relaxed)).ok() == Some(key.len());
+        let same_words = self
+            .words
+            .iter()
+            .zip(Self::words_of(key))
+            .all(|(word, value)| word.load(Ordering::Relaxed) == value);
+        fence(Ordering::Acquire);
+        same_len && same_words && self.holder.load(Ordering::Relaxed) == holder
+    }
+}
+
+/// `Mapping` is a shared memory mapping of a file
+#[derive(Debug)]
+struct Mapping {
+    /// the first byte
+    ptr: *mut u8,
+    /// the mapped length
+    len: usize,
+}
+
+// SAFETY: the mapping is only accessed through atomics, and the bytes of
+// a slot are only touched by the process owning it through its sequence
+unsafe impl Send for Mapping {}
+// SAFETY: see `Send`
+unsafe impl Sync for Mapping {}
+
+impl Mapping {
+    /// map `len` bytes of `file` shared with other processes
+    fn map(file: &File, len: usize) -> io::Result<Mapping> {
+        // SAFETY: a new mapping of an open file, checked for failure below
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr.cast::<u8>(),
+            len,
+        })
+    }
+
+    /// the `U` at `offset`, the mapping is page aligned and the caller
+    /// keeps `offset` aligned for `U`
+    #[allow(clippy::cast_ptr_alignment)] // checked by the assertion below
+    fn get<U>(&self, offset: usize) -> &U {
+        let ptr = self.ptr.wrapping_add(offset).cast::<U>();
+        assert!(
+            offset.saturating_add(size_of::<U>()) <= self.len && ptr.is_aligned(),
+            "invalid offset {offset} in the mapping"
+        );
+        // SAFETY: it is inside the mapping and aligned, `U` is an atomic
+        // or only has atomics, so any bytes are a valid `U`
+        unsafe { &*ptr }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: the mapping was created by `map` and is not used any more
+        let _res = unsafe { libc::munmap(self.ptr.cast::<libc::c_void>(), self.len) };
+    }
+}
+
+/// sleep while the futex `word` is `expected`, it may return spuriously
+fn futex_wait(word: &AtomicU32, expected: u32) {
+    // SAFETY: `word` is an aligned u32 in a shared mapping, the futex is
+    // not private so it works across processes
+    let _res = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT,
+            expected,
+            ptr::null::<libc::timespec>(),
+        )
+    };
+}
+
+/// wake up to `count` sleepers of the futex `word`
+fn futex_wake(word: &AtomicU32, count: i32) {
+    // SAFETY: see `futex_wait`
+    let _res = unsafe { libc::syscall(libc::SYS_futex, word.as_ptr(), libc::FUTEX_WAKE, count) };
+}
+
+/// `Ring` is a bounded multi producer ring buffer in shared memory.
+/// a slot with sequence `pos` is free for the producer claiming `pos`,
+/// and with sequence `pos + 1` it holds the message for the consumer.
+/// the key table lies between the header and the slots
+#[derive(Debug)]
+struct Ring {
+    /// the shared mapping
+    mapping: Mapping,
+    /// how many slots the ring has
+    slots: u64,
+    /// the max size of a message in a slot
+    slot_size: usize,
+    /// the distance between two slots
+    stride: usize,
+    /// how many entries the key table has
+    key_entries: usize,
+    /// the offset of the first slot
+    slots_offset: usize,
+}
+
+impl Ring {
+    /// the mapping size of a ring and its slot stride
+    fn layout(slots: u64, slot_size: usize) -> Option<(usize, usize)> {
+        let stride = SLOT_HEADER_SIZE
+            .checked_add(slot_size)?
+            .checked_next_multiple_of(SLOT_ALIGN)?;
+        let len = usize::try_from(slots)
+            .ok()?
+            .checked_mul(stride)?
+            .checked_add(Ring::slots_offset(slots)?)?;
+        Some((stride, len))
+    }
+
+    /// how many entries the key table of a ring with `slots` slots has
+    fn key_entries(slots: u64) -> Option<usize> {
+        usize::try_from(slots.checked_mul(KEY_ENTRIES_PER_SLOT)?).ok()
+    }
+
+    /// the offset of the first slot, after the header and the key table
+    fn slots_offset(slots: u64) -> Option<usize> {
+        Ring::key_entries(slots)?
+            .checked_mul(size_of::<KeyEntry>())?
+            .checked_add(HEADER_SIZE)
+    }
+
+    /// create the ring file at `path`, it replaces an existing one
+    fn create(path: &Path, slots: u64, slot_size: usize) -> io::Result<Ring> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid ring size");
+        if slots == 0 || u32::try_from(slot_size).is_err() {
+            return Err(invalid());
+        }
+        let (stride, len) = Ring::layout(slots, slot_size).ok_or_else(invalid)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(u64::try_from(len).map_err(|_err| invalid())?)?;
+        let ring = Ring {
+            mapping: Mapping::map(&file, len)?,
+            slots,
+            slot_size,
+            stride,
+            key_entries: Ring::key_entries(slots).unwrap_or(0),
+            slots_offset: Ring::slots_offset(slots).unwrap_or(len),
+        };
+        let header = ring.header();
+        header.slots.store(slots, Ordering::Relaxed);
+        header
+            .slot_size
+            .store(u64::try_from(slot_size).unwrap_or(0), Ordering::Relaxed);
+        for pos in 0..slots {
+            ring.sequence(pos).store(pos, Ordering::Relaxed);
+        }
+        // the producers check the magic before anything else
+        header.magic.store(SHM_MAGIC, Ordering::Release);
+        Ok(ring)
+    }
+
+    /// open the ring file at `path` created by a consumer
+    fn open(path: &Path) -> io::Result<Ring> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a keyed channel ring");
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let file_len = usize::try_from(file.metadata()?.len()).map_err(|_err| invalid())?;
+        if file_len < HEADER_SIZE {
+            return Err(invalid());
+        }
+        let mapping = Mapping::map(&file, file_len)?;
+        let header = mapping.get::<Header>(0);
+        if header.magic.load(Ordering::Acquire) != SHM_MAGIC {
+            return Err(invalid());
+        }
+        let slots = header.slots.load(Ordering::Relaxed);
+        let slot_size =
+            usize::try_from(header.slot_size.load(Ordering::Relaxed)).map_err(|_err| invalid())?;
+        match Ring::layout(slots, slot_size) {
+            Some((stride, len)) if slots > 0 && len <= file_len => Ok(Ring {
+                mapping,
+                slots,
+                slot_size,
+                stride,
+                key_entries: Ring::key_entries(slots).unwrap_or(0),
+                slots_offset: Ring::slots_offset(slots).unwrap_or(len),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    /// the shared header
+    fn header(&self) -> &Header {
+        self.mapping.get(0)
+    }
+
+    /// the entry `index` of the key table
+    fn key_entry(&self, index: usize) -> &KeyEntry {
+        self.mapping.get(
+            index
+                .wrapping_mul(size_of::<KeyEntry>())
+                .wrapping_add(HEADER_SIZE),
+        )
+    }
+
+    /// whether a recieved message holds `key`
+    fn is_held(&self, key: &str) -> bool {
+        (0..self.key_entries).any(|index| self.key_entry(index).is_held(key))
+    }
+
+    /// whether the keys of `message` can be held in the key table, a
+    /// message which never fits would block its keys forever
+    fn fits_key_table<T>(&self, message: &InternalMessage<T>) -> bool {
+        let keys: HashSet<&str> = message.key_names().into_iter().collect();
+        keys.len() <= self.key_entries && keys.iter().all(|key| key.len() <= KEY_SIZE)
+    }
+
+    /// the offset of the slot for `pos`
+    fn slot(&self, pos: u64) -> usize {
+        let index = usize::try_from(pos.checked_rem(self.slots).unwrap_or(0)).unwrap_or(0);
+        index
+            .wrapping_mul(self.stride)
+            .wrapping_add(self.slots_offset)
+    }
+
+    /// the first message byte of the slot for `pos`
+    fn data(&self, pos: u64) -> *mut u8 {
+        self.mapping
+            .ptr
+            .wrapping_add(self.slot(pos).wrapping_add(SLOT_HEADER_SIZE))
+    }
+
+    /// the sequence of the slot for `pos`
+    fn sequence(&self, pos: u64) -> &AtomicU64 {
+        self.mapping.get(self.slot(pos))
+    }
+
+    /// the message length of the slot for `pos`, it follows the sequence
+    fn length(&self, pos: u64) -> &AtomicU32 {
+        self.mapping.get(self.slot(pos).wrapping_add(8))
+    }
+
+    /// write a message, wait while the ring is full
+    fn push(&self, bytes: &[u8]) -> Result<()> {
+        let len = u32::try_from(bytes.len()).or(Err(Errors::ExceedCapacity))?;
+        if bytes.len() > self.slot_size {
+            return Err(Errors::ExceedCapacity);
+        }
+        let header = self.header();
+        loop {
+            // read the futex word first, so a slot freed after the
+            // check below changes it and the wait returns at once
+            let seen = header.not_full.load(Ordering::SeqCst);
+            let pos = header.enqueue_pos.load(Ordering::Relaxed);
+            let sequence = self.sequence(pos).load(Ordering::Acquire);
+            match sequence.cmp(&pos) {
+                CmpOrdering::Equal => {
+                    let claimed = header.enqueue_pos.compare_exchange_weak(
+                        pos,
+                        pos.wrapping_add(1),
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    );
+                    if claimed.is_ok() {
+                        // SAFETY: the slot is claimed by this producer and large enough
+                        unsafe {
+                            ptr::copy_nonoverlapping(bytes.as_ptr(), self.data(pos), bytes.len());
+                        }
+                        self.length(pos).store(len, Ordering::Relaxed);
+                        self.sequence(pos)
+                            .store(pos.wrapping_add(1), Ordering::Release);
+                        let _ = header.not_empty.fetch_add(1, Ordering::SeqCst);
+                        if header.consumer_waiting.load(Ordering::SeqCst) > 0 {
+                            futex_wake(&header.not_empty, 1);
+                        }
+                        return Ok(());
+                    }
+                }
+                CmpOrdering::Less => {
+                    // the consumer has not freed the slot yet, the ring is full
+                    let _ = header.producers_waiting.fetch_add(1, Ordering::SeqCst);
+                    futex_wait(&header.not_full, seen);
+                    let _ = header.producers_waiting.fetch_sub(1, Ordering::SeqCst);
+                }
+                // another producer took the position, try the next one
+                CmpOrdering::Greater => {}
+            }
+        }
+    }
+
+    /// read the next message if there is one, only the consumer calls it
+    fn try_pop(&self) -> Option<Vec<u8>> {
+        let header = self.header();
+        let pos = header.dequeue_pos.load(Ordering::Relaxed);
+        if self.sequence(pos).load(Ordering::Acquire) != pos.wrapping_add(1) {
+            return None;
+        }
+        let len = usize::try_from(self.length(pos).load(Ordering::Relaxed))
+            .unwrap_or(0)
+            .min(self.slot_size);
+        let mut bytes = vec![0_u8; len];
+        // SAFETY: the slot is published to the consumer and holds `len` bytes
+        unsafe {
+            ptr::copy_nonoverlapping(self.data(pos), bytes.as_mut_ptr(), len);
+        }
+        self.sequence(pos)
+            .store(pos.wrapping_add(self.slots), Ordering::Release);
+        header
+            .dequeue_pos
+            .store(pos.wrapping_add(1), Ordering::Relaxed);
+        let _ = header.not_full.fetch_add(1, Ordering::SeqCst);
+        if header.producers_waiting.load(Ordering::SeqCst) > 0 {
+            futex_wake(&header.not_full, i32::MAX);
+        }
+        Some(bytes)
+    }
+
+    /// sleep until a message is written, `seen` is the `not_empty` word
+    /// read before the ring was found empty
+    fn wait_not_empty(&self, seen: u32) {
+        let header = self.header();
+        let _ = header.consumer_waiting.fetch_add(1, Ordering::SeqCst);
+        futex_wait(&header.not_empty, seen);
+        let _ = header.consumer_waiting.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// `ShmSender` sends messages from any process on the host to the
+/// `ShmReciever` which created the ring
+#[derive(Debug)]
+pub struct ShmSender<T> {
+    /// the shared ring
+    ring: Ring,
+    /// the type of the message data
+    data: PhantomData<fn(T)>,
+}
+
+impl<T: Persist> ShmSender<T> {
+    /// `open` maps the ring at `path` created by `ShmReciever::create`
+    ///
+    /// # Errors
+    ///
+    /// it fails when the file can not be mapped or is not a ring
+    #[inline]
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<ShmSender<T>> {
+        Ok(ShmSender {
+            ring: Ring::open(path.as_ref())?,
+            data: PhantomData,
+        })
+    }
+
+    /// `send` writes a message to the ring, it waits while the ring is full.
+    /// the id of the message is given by the reciever's process
+    ///
+    /// # Errors
+    ///
+    /// it fails with `ExceedCapacity` when the message is larger than a slot,
+    /// a key is longer than 48 bytes or it has more keys than the key table
+    /// holds
+    #[inline]
+    #[allow(clippy::needless_pass_by_value)] // the message is sent away like `Sender::send`
+    pub fn send(&self, message: InternalMessage<T>) -> Result<()> {
+        if !self.ring.fits_key_table(&message) {
+            return Err(Errors::ExceedCapacity);
+        }
+        let body = encode_body(&message).or(Err(Errors::ExceedCapacity))?;
+        self.ring.push(&body)
+    }
+
+    /// `is_key_active` reads the key table of the ring, whether a message
+    /// recieved by the consumer still holds `key`
+    #[inline]
+    #[must_use]
+    pub fn is_key_active(&self, key: &str) -> bool {
+        self.ring.is_held(key)
+    }
+}
+
+/// `SharedKeys` lets the consumer write the key table of its ring
+#[derive(Debug)]
+struct SharedKeys {
+    /// the ring holding the key table
+    ring: Arc<Ring>,
+    /// the consumer's index of the table
+    index: Mutex<KeyIndex>,
+}
+
+/// `KeyIndex` finds the entries of the held keys without reading the
+/// whole table
+#[derive(Debug)]
+struct KeyIndex {
+    /// held key -> its entry
+    entries: HashMap<String, usize>,
+    /// the free entries
+    free: Vec<usize>,
+    /// the token of the last message holding keys
+    holder: u64,
+}
+
+impl SharedKeys {
+    /// the keys of a new ring, none is held
+    fn new(ring: Arc<Ring>) -> SharedKeys {
+        let free = (0..ring.key_entries).rev().collect();
+        SharedKeys {
+            ring,
+            index: Mutex::new(KeyIndex {
+                entries: HashMap::new(),
+                free,
+                holder: 0,
+            }),
+        }
+    }
+
+    /// take the first pending message whose keys are free, it holds them
+    /// in the table after. a key held by an earlier pending message is not
+    /// free, so every key keeps its send order. a message whose keys do
+    /// not fit in the free entries waits like a blocked one
+    fn take_free<T>(
+        &self,
+        pending: &mut VecDeque<InternalMessage<T>>,
+    ) -> Option<InternalMessage<T>> {
+        let mut index = self.index.lock();
+        let mut earlier = HashSet::new();
+        let position = pending.iter().position(|message| {
+            let keys: HashSet<&str> = message.key_names().into_iter().collect();
+            let free = keys
+                .iter()
+                .all(|key| !earlier.contains(key) && !index.entries.contains_key(*key));
+            let fits = keys.len() <= index.free.len();
+            earlier.extend(keys);
+            free && fits
+        })?;
+        let message = pending.remove(position)?;
+        index.holder = index.holder.wrapping_add(1);
+        let holder = index.holder;
+        for key in message.key_names() {
+            if index.entries.contains_key(key) {
+                continue;
+            }
+            // the entries were counted above
+            if let Some(entry) = index.free.pop() {
+                self.ring.key_entry(entry).hold(key, holder);
+                let _ = index.entries.insert(String::from(key), entry);
+            }
+        }
+        Some(message)
+    }
+
+    /// free the entry of `key`, its message released it
+    fn release(&self, key: &str) {
+        let mut index = self.index.lock();
+        if let Some(entry) = index.entries.remove(key) {
+            self.ring.key_entry(entry).release();
+            index.free.push(entry);
+        }
+    }
+}
+
+/// `ShmReciever` is the single consumer of a shared memory ring. the
+/// keys held by its recieved messages are kept in the key table of the
+/// mapping, which decides what it gives out, so every process sees them.
+/// a message taken from the ring waits in this process while its keys
+/// are held, then it is given out through an in-process channel, which
+/// frees its keys in the table when the message is dropped
+#[derive(Debug)]
+pub struct ShmReciever<T> {
+    /// the shared ring
+    ring: Arc<Ring>,
+    /// the key table of the ring
+    keys: Arc<SharedKeys>,
+    /// the messages taken from the ring, in send order
+    pending: Mutex<VecDeque<InternalMessage<T>>>,
+    /// gives the messages out
+    sender: Sender<T>,
+    /// recieves the messages given out
+    reciever: Reciever<T>,
+}
+
+impl<T> ShmReciever<T>
+where
+    T: Persist + Debug,
+{
+    /// `create` creates a ring of `slots` messages of at most `slot_size`
+    /// bytes (keys included) in the file at `path`, usually under
+    /// `/dev/shm`. an existing file is replaced, the caller removes it
+    ///
+    /// # Errors
+    ///
+    /// it fails when the sizes are invalid or the file can not be mapped
+    #[inline]
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        slots: u64,
+        slot_size: usize,
+    ) -> io::Result<ShmReciever<T>> {
+        let ring = Arc::new(Ring::create(path.as_ref(), slots, slot_size)?);
+        debug!(path:? = path.as_ref(), slots = slots, slot_size = slot_size; "shared memory ring created");
+        let keys = Arc::new(SharedKeys::new(Arc::clone(&ring)));
+        let (sender, reciever) = MspcChannel::<T>::channel(-1);
+        let released = Arc::clone(&keys);
+        reciever
+            .chan()
+            .set_release_handler(move |key| released.release(key));
+        Ok(ShmReciever {
+            ring,
+            keys,
+            pending: Mutex::new(VecDeque::new()),
+            sender,
+            reciever,
+        })
+    }
+
+    /// move messages from the ring into `pending`, at most as many as
+    /// the ring holds wait, so producers still feel backpressure
+    fn drain(&self, pending: &mut VecDeque<InternalMessage<T>>) {
+        let capacity = usize::try_from(self.ring.slots).unwrap_or(usize::MAX);
+        while pending.len() < capacity {
+            let Some(body) = self.ring.try_pop() else {
+                break;
+            };
+            match decode_message::<T>(&body) {
+                Some(message) if self.ring.fits_key_table(&message) => {
+                    pending.push_back(message);
+                }
+                Some(_) => error!("a shared memory message does not fit in the key table"),
+                None => error!("a shared memory message can not be decoded"),
+            }
+        }
+    }
+
+    /// `recv` recieves a message like `Reciever::recv`, it sleeps on a
+    /// futex while no message is queued
+    ///
+    /// # Errors
+    ///
+    /// it fails with `KeyDuplicate` when all queued messages are blocked
+    #[inline]
+    pub fn recv(&self) -> Result<InternalMessage<T>> {
+        loop {
+            let seen = self.ring.header().not_empty.load(Ordering::SeqCst);
+            let mut pending = self.pending.lock();
+            self.drain(&mut pending);
+            if pending.is_empty() {
+                drop(pending);
+                self.ring.wait_not_empty(seen);
+                continue;
+            }
+            let message = self
+                .keys
+                .take_free(&mut pending)
+                .ok_or(Errors::KeyDuplicate)?;
+            drop(pending);
+            // its keys are free in the table, so the channel gives it out at once
+            let _ = self.sender.send(message)?;
+            return self.reciever.try_recv();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_shm {
+    use super::Ring;
+    use crate::errors::Errors;
+
+    #[test]
+    fn test_ring_wraps_around() {
+        let path =
+            std::env::temp_dir().join(format!("personal_channel_{}_ring", std::process::id()));
+        let ring = Ring::create(&path, 2, 8);
+        assert!(ring.is_ok());
+        let consumer = ring.unwrap_or_else(|err| panic!("{err}"));
+        let producer = Ring::open(&path).unwrap_or_else(|err| panic!("{err}"));
+        for round in 0_u8..3 {
+            assert_eq!(producer.push(&[round]), Ok(()));
+            assert_eq!(producer.push(&[round, round]), Ok(()));
+            assert_eq!(consumer.try_pop(), Some(vec![round]));
+            assert_eq!(consumer.try_pop(), Some(vec![round, round]));
+            assert_eq!(consumer.try_pop(), None);
+        }
+        assert_eq!(producer.push(&[0; 9]), Err(Errors::ExceedCapacity));
+        assert!(std::fs::remove_file(&path).is_ok());
+        // not a ring
+        assert!(Ring::open(&path).is_err());
+    }
+}
@@ -0,0 +1,212 @@
+use std::fmt::Write;
+
+/// `ChannelSnapshot` is a consistent read-only view of a channel,
+/// it is taken while holding the channel's lock
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct ChannelSnapshot {
+    /// queued messages, the valid ones in the order `recv` gives them
+    /// out now, followed by the blocked ones in send order
+    pub queued: Vec<QueuedMessage>,
+    /// keys held by recieved messages, sorted by key
+    pub active_keys: Vec<ActiveKey>,
+}
+
+/// `QueuedMessage` describes a message waiting in the channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct QueuedMessage {
+    /// the message id
+    pub id: u64,
+    /// the message keys
+    pub keys: Vec<String>,
+    /// whether `recv` can give it out now
+    pub eligible: bool,
+    /// its keys held by recieved messages
+    pub blocked_by_keys: Vec<String>,
+    /// the queued messages it waits behind, one per shared key
+    pub waiting_behind: Vec<u64>,
+}
+
+/// `ActiveKey` is a key held by a recieved message
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ActiveKey {
+    /// the key
+    pub key: String,
+    /// the id of the recieved message holding it
+    pub holder: u64,
+}
+
+impl ChannelSnapshot {
+    /// `to_dot` renders the blocking graph as a Graphviz digraph: active keys
+    /// point to the recieved messages holding them, and queued messages point
+    /// to the active keys and earlier messages they are blocked by
+    #[inline]
+    #[must_use]
+    pub fn to_dot(&self) -> String {
+        let mut lines = vec![String::from("digraph channel {")];
+        for active in &self.active_keys {
+            lines.push(std::format!(
+                "    {} [shape=box, label={}];",
+                quote(&std::format!("key:{}", active.key)),
+                quote(&active.key)
+            ));
+            lines.push(std::format!(
+                "    {} -> \"message:{}\" [label=\"held by\"];",
+                quote(&std::format!("key:{}", active.key)),
+                active.holder
+            ));
+            lines.push(std::format!(
+                "    \"message:{}\" [style=dashed, label=\"#{} recieved\"];",
+                active.holder,
+                active.holder
+            ));
+        }
+        for message in &self.queued {
+            let color = if message.eligible { "green" } else { "red" };
+            let label = std::format!("#{} [{}]", message.id, message.keys.join(", "));
+            lines.push(std::format!(
+                "    \"message:{}\" [color={color}, label={}];",
+                message.id,
+                quote(&label)
+            ));
+            for key in &message.blocked_by_keys {
+                lines.push(std::format!(
+                    "    \"message:{}\" -> {} [label=\"blocked by\"];",
+                    message.id,
+                    quote(&std::format!("key:{key}"))
+                ));
+            }
+            for earlier in &message.waiting_behind {
+                lines.push(std::format!(
+                    "    \"message:{}\" -> \"message:{earlier}\" [label=\"waits behind\"];",
+                    message.id
+                ));
+            }
+        }
+        lines.push(String::from("}"));
+        lines.join("\n")
+    }
+
+    /// `to_json` renders the snapshot as a JSON object with the
+    /// `queued` and `active_keys` arrays
+    #[inline]
+    #[must_use]
+    pub fn to_json(&self) -> String {
+        let queued: Vec<String> = self
+            .queued
+            .iter()
+            .map(|message| {
+                std::format!(
+                    "{{\"id\":{},\"keys\":{},\"eligible\":{},\"blocked_by_keys\":{},\"waiting_behind\":{}}}",
+                    message.id,
+                    json_strings(&message.keys),
+                    message.eligible,
+                    json_strings(&message.blocked_by_keys),
+                    json_ids(&message.waiting_behind)
+                )
+            })
+            .collect();
+        let active_keys: Vec<String> = self
+            .active_keys
+            .iter()
+            .map(|active| {
+                std::format!(
+                    "{{\"key\":{},\"holder\":{}}}",
+                    quote(&active.key),
+                    active.holder
+                )
+            })
+            .collect();
+        std::format!(
+            "{{\"queued\":[{}],\"active_keys\":[{}]}}",
+            queued.join(","),
+            active_keys.join(",")
+        )
+    }
+}
+
+/// quote and escape a string, the result is valid for both DOT and JSON
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len().saturating_add(2));
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            // writing into a `String` never fails
+            c if c.is_control() => write!(quoted, "\\u{:04x}", u32::from(c)).unwrap_or_default(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// render strings as a JSON array
+fn json_strings(strings: &[String]) -> String {
+    let quoted: Vec<String> = strings.iter().map(|text| quote(text)).collect();
+    std::format!("[{}]", quoted.join(","))
+}
+
+/// render ids as a JSON array
+fn json_ids(ids: &[u64]) -> String {
+    let ids: Vec<String> = ids.iter().map(u64::to_string).collect();
+    std::format!("[{}]", ids.join(","))
+}
+
+#[cfg(test)]
+mod test_snapshot {
+    use super::{ActiveKey, ChannelSnapshot, QueuedMessage};
+
+    fn sample() -> ChannelSnapshot {
+        ChannelSnapshot {
+            queued: vec![
+                QueuedMessage {
+                    id: 2,
+                    keys: vec![String::from("a"), String::from("b")],
+                    eligible: false,
+                    blocked_by_keys: vec![String::from("a")],
+                    waiting_behind: Vec::new(),
+                },
+                QueuedMessage {
+                    id: 3,
+                    keys: vec![String::from("b\"")],
+                    eligible: false,
+                    blocked_by_keys: Vec::new(),
+                    waiting_behind: vec![2],
+                },
+            ],
+            active_keys: vec![ActiveKey {
+                key: String::from("a"),
+                holder: 1,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let dot = sample().to_dot();
+        assert!(dot.starts_with("digraph channel {\n"));
+        assert!(dot.contains("\"key:a\" -> \"message:1\" [label=\"held by\"];"));
+        assert!(dot.contains("\"message:2\" -> \"key:a\" [label=\"blocked by\"];"));
+        assert!(dot.contains("\"message:3\" -> \"message:2\" [label=\"waits behind\"];"));
+        assert!(dot.contains("label=\"#3 [b\\\"]\""));
+        assert!(dot.ends_with('}'));
+    }
+
+    #[test]
+    fn test_to_json() {
+        assert_eq!(
+            sample().to_json(),
+            "{\"queued\":[\
+             {\"id\":2,\"keys\":[\"a\",\"b\"],\"eligible\":false,\"blocked_by_keys\":[\"a\"],\"waiting_behind\":[]},\
+             {\"id\":3,\"keys\":[\"b\\\"\"],\"eligible\":false,\"blocked_by_keys\":[],\"waiting_behind\":[2]}\
+             ],\"active_keys\":[{\"key\":\"a\",\"holder\":1}]}"
+        );
+    }
+}
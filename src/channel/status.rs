@@ -0,0 +1,79 @@
+use std::collections::{HashSet, VecDeque};
+
+/// how many completed message ids a channel remembers
+pub(crate) const COMPLETED_HISTORY_SIZE: usize = 1024;
+
+/// `MessageStatus` tells where a message is in its lifecycle
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum MessageStatus {
+    /// the message is in the channel and can be recieved
+    Queued,
+    /// the message is in the channel, but these keys block it, each is
+    /// held by a recieved message or wanted by an earlier queued one
+    Blocked(Vec<String>),
+    /// the message has been recieved and still holds some of its keys
+    Active,
+    /// the message has been recieved and all its keys are released
+    Completed,
+    /// the message was dropped by the overflow policy of a full channel
+    Dropped,
+    /// the id was never sent, or it is too old to be remembered
+    Unknown,
+}
+
+/// `CompletedHistory` keeps the latest completed message ids,
+/// the oldest one will be forgotten when it is full
+#[derive(Debug)]
+pub(crate) struct CompletedHistory {
+    /// completed ids in completion order
+    order: VecDeque<u64>,
+    /// the same ids, used for fast lookup
+    ids: HashSet<u64>,
+    /// the max number of ids we remember
+    capacity: usize,
+}
+
+impl CompletedHistory {
+    /// create a history which remembers at most `capacity` ids
+    pub(crate) fn new(capacity: usize) -> CompletedHistory {
+        CompletedHistory {
+            order: VecDeque::with_capacity(capacity),
+            ids: HashSet::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// record a completed message id
+    pub(crate) fn push(&mut self, id: u64) {
+        if self.capacity == 0 || !self.ids.insert(id) {
+            return;
+        }
+        if self.order.len() == self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                let _ = self.ids.remove(&oldest);
+            }
+        }
+        self.order.push_back(id);
+    }
+
+    /// check whether `id` is remembered as completed
+    pub(crate) fn contains(&self, id: u64) -> bool {
+        self.ids.contains(&id)
+    }
+}
+
+#[cfg(test)]
+mod test_status {
+    use super::CompletedHistory;
+    #[test]
+    fn test_completed_history_is_bounded() {
+        let mut history = CompletedHistory::new(2);
+        history.push(1);
+        history.push(2);
+        history.push(3);
+        assert!(!history.contains(1));
+        assert!(history.contains(2));
+        assert!(history.contains(3));
+    }
+}
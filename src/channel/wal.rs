@@ -0,0 +1,499 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+/// a record with one or more sent messages
+const TAG_SEND: u8 = 1;
+/// a record of a recieved message
+const TAG_DELIVER: u8 = 2;
+/// a record of a released key
+const TAG_RELEASE: u8 = 3;
+/// a record of the last message id given out
+const TAG_LAST_ID: u8 = 4;
+/// a record of a dropped message
+const TAG_DROP: u8 = 5;
+/// every frame starts with the body length and the body checksum
+const FRAME_HEADER_SIZE: usize = 8;
+
+/// `Persist` turns the data of a message into bytes and back, it is
+/// needed by a durable channel to write messages to its log
+pub trait Persist: Sized {
+    /// encode the data
+    fn to_bytes(&self) -> Vec<u8>;
+    /// decode the data, `None` means the bytes are not valid
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+impl Persist for Vec<u8> {
+    #[inline]
+    fn to_bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+
+    #[inline]
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(bytes.to_vec())
+    }
+}
+
+impl Persist for String {
+    #[inline]
+    fn to_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+
+    #[inline]
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        String::from_utf8(bytes.to_vec()).ok()
+    }
+}
+
+/// implement `Persist` for integers with their little endian bytes
+macro_rules! persist_integer {
+    ($($integer:ty),*) => {
+        $(
+            impl Persist for $integer {
+                #[inline]
+                fn to_bytes(&self) -> Vec<u8> {
+                    self.to_le_bytes().to_vec()
+                }
+
+                #[inline]
+                fn from_bytes(bytes: &[u8]) -> Option<Self> {
+                    Some(<$integer>::from_le_bytes(bytes.try_into().ok()?))
+                }
+            }
+        )*
+    };
+}
+
+persist_integer!(i32, i64, u32, u64, usize);
+
+/// `LoggedMessage` is a message as it is written in the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct LoggedMessage {
+    /// the message id
+    pub(crate) id: u64,
+    /// the message keys
+    pub(crate) keys: Vec<String>,
+    /// the encoded message data
+    pub(crate) payload: Vec<u8>,
+}
+
+/// `Record` is one entry of the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Record {
+    /// messages enqueued together, a batch is all-or-nothing
+    Send(Vec<LoggedMessage>),
+    /// a message is recieved
+    Deliver(u64),
+    /// a recieved message released one of its keys
+    Release(u64, String),
+    /// no message id up to this one can be given out again
+    LastId(u64),
+    /// a queued message is dropped by the overflow policy
+    Drop(u64),
+}
+
+/// `Recovered` is what a log tells after a restart
+#[derive(Debug, Default)]
+pub(crate) struct Recovered {
+    /// the messages to deliver again, in the order they were enqueued
+    pub(crate) messages: Vec<LoggedMessage>,
+    /// the largest message id in the log
+    pub(crate) last_id: u64,
+}
+
+/// `WriteAheadLog` appends records to a local file, each record is
+/// framed with its length and a crc32 checksum so a torn or corrupt
+/// tail left by a crash is detected and dropped on recovery
+#[derive(Debug)]
+pub(crate) struct WriteAheadLog {
+    /// the log file, opened for appending
+    file: File,
+    /// the length of the records written whole
+    len: u64,
+    /// a failed write could not be cut off, so nothing is appended after it
+    poisoned: bool,
+}
+
+impl WriteAheadLog {
+    /// open the log at `path` and replay it. the log is compacted to the
+    /// messages which still have to be delivered before it is reused
+    pub(crate) fn open(path: &Path) -> io::Result<(WriteAheadLog, Recovered)> {
+        let recovered = match fs::read(path) {
+            Ok(bytes) => replay(&decode_records(&bytes)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Recovered::default(),
+            Err(err) => return Err(err),
+        };
+        // write the compacted log aside and swap it in, so a crash
+        // while compacting keeps the old log
+        let compacted = compacting_path(path);
+        let mut bytes = encode_record(&Record::LastId(recovered.last_id))?;
+        if !recovered.messages.is_empty() {
+            bytes.extend(encode_record(&Record::Send(recovered.messages.clone()))?);
+        }
+        {
+            let mut file = File::create(&compacted)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+        }
+        fs::rename(&compacted, path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        let len = file.metadata()?.len();
+        Ok((
+            WriteAheadLog {
+                file,
+                len,
+                poisoned: false,
+            },
+            recovered,
+        ))
+    }
+
+    /// append a record, it is written with one `write` call
+    /// so that the records of different threads never interleave
+    pub(crate) fn append(&mut self, record: &Record) -> io::Result<()> {
+        if self.poisoned {
+            return Err(io::Error::other("a failed write is left in the log"));
+        }
+        let bytes = encode_record(record)?;
+        match self.file.write_all(&bytes) {
+            Ok(()) => {
+                let written = u64::try_from(bytes.len()).unwrap_or(u64::MAX);
+                self.len = self.len.saturating_add(written);
+                Ok(())
+            }
+            Err(err) => {
+                self.cut_torn_record();
+                Err(err)
+            }
+        }
+    }
+
+    /// cut off what a failed write left, the recovery stops at a torn
+    /// record and would lose every record appended after it. a log which
+    /// can not be cut is poisoned
+    fn cut_torn_record(&mut self) {
+        if self.file.set_len(self.len).is_err() {
+            self.poisoned = true;
+        }
+    }
+
+    /// flush the log to the disk, so it survives a power loss as well
+    pub(crate) fn sync(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// the path the compacted log is written to
+fn compacting_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(".compacting");
+    PathBuf::from(name)
+}
+
+/// find the messages which are not completed: a message is completed
+/// once it is recieved and all its keys are released, the others are
+/// delivered again, so a message may be delivered more than once
+pub(crate) fn replay(records: &[Record]) -> Recovered {
+    /// a logged message and what happened to it
+    struct Pending {
+        /// the message
+        message: LoggedMessage,
+        /// it was recieved
+        delivered: bool,
+        /// the keys it released
+        released: HashSet<String>,
+    }
+    let mut last_id = 0;
+    let mut order = Vec::new();
+    let mut pending: HashMap<u64, Pending> = HashMap::new();
+    for record in records {
+        match *record {
+            Record::Send(ref messages) => {
+                for message in messages {
+                    last_id = last_id.max(message.id);
+                    order.push(message.id);
+                    let _ = pending.insert(
+                        message.id,
+                        Pending {
+                            message: message.clone(),
+                            delivered: false,
+                            released: HashSet::new(),
+                        },
+                    );
+                }
+            }
+            Record::Deliver(id) => {
+                if let Some(entry) = pending.get_mut(&id) {
+                    entry.delivered = true;
+                }
+            }
+            Record::Release(id, ref key) => {
+                if let Some(entry) = pending.get_mut(&id) {
+                    let _ = entry.released.insert(String::from(key));
+                }
+            }
+            Record::LastId(id) => last_id = last_id.max(id),
+            Record::Drop(id) => {
+                let _ = pending.remove(&id);
+            }
+        }
+    }
+    let messages = order
+        .into_iter()
+        .filter_map(|id| pending.remove(&id))
+        .filter(|entry| {
+            !entry.delivered
+                || entry
+                    .message
+                    .keys
+                    .iter()
+                    .any(|key| !entry.released.contains(key))
+        })
+        .map(|entry| entry.message)
+        .collect();
+    Recovered { messages, last_id }
+}
+
+/// crc32 (IEEE) of `bytes`
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+/// append a length prefixed field
+pub(crate) fn put_bytes(body: &mut Vec<u8>, bytes: &[u8]) -> io::Result<()> {
+    body.extend(length(bytes.len())?.to_le_bytes());
+    body.extend(bytes);
+    Ok(())
+}
+
+/// a length written in the log
+pub(crate) fn length(len: usize) -> io::Result<u32> {
+    u32::try_from(len).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+}
+
+/// encode a record into a frame
+pub(crate) fn encode_record(record: &Record) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    match *record {
+        Record::Send(ref messages) => {
+            body.push(TAG_SEND);
+            body.extend(length(messages.len())?.to_le_bytes());
+            for message in messages {
+                body.extend(message.id.to_le_bytes());
+                body.extend(length(message.keys.len())?.to_le_bytes());
+                for key in &message.keys {
+                    put_bytes(&mut body, key.as_bytes())?;
+                }
+                put_bytes(&mut body, &message.payload)?;
+            }
+        }
+        Record::Deliver(id) => {
+            body.push(TAG_DELIVER);
+            body.extend(id.to_le_bytes());
+        }
+        Record::Release(id, ref key) => {
+            body.push(TAG_RELEASE);
+            body.extend(id.to_le_bytes());
+            put_bytes(&mut body, key.as_bytes())?;
+        }
+        Record::LastId(id) => {
+            body.push(TAG_LAST_ID);
+            body.extend(id.to_le_bytes());
+        }
+        Record::Drop(id) => {
+            body.push(TAG_DROP);
+            body.extend(id.to_le_bytes());
+        }
+    }
+    let mut frame = Vec::with_capacity(body.len().saturating_add(FRAME_HEADER_SIZE));
+    frame.extend(length(body.len())?.to_le_bytes());
+    frame.extend(crc32(&body).to_le_bytes());
+    frame.extend(body);
+    Ok(frame)
+}
+
+/// decode the frames of a log, stop at the first torn or corrupt one
+pub(crate) fn decode_records(bytes: &[u8]) -> Vec<Record> {
+    let mut records = Vec::new();
+    let mut reader = Reader(bytes);
+    while let Some(record) = reader.frame() {
+        records.push(record);
+    }
+    records
+}
+
+/// `Reader` reads fields from the front of a byte slice
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    /// take the next `len` bytes
+    pub(crate) fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let taken = self.0.get(..len)?;
+        self.0 = self.0.get(len..)?;
+        Some(taken)
+    }
+
+    /// take a little endian u32
+    pub(crate) fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    /// take a little endian u64
+    pub(crate) fn u64(&mut self) -> Option<u64> {
+        Some(u64::from_le_bytes(self.take(8)?.try_into().ok()?))
+    }
+
+    /// take a length prefixed field
+    pub(crate) fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = usize::try_from(self.u32()?).ok()?;
+        self.take(len)
+    }
+
+    /// take a length prefixed utf-8 string
+    pub(crate) fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?.to_vec()).ok()
+    }
+
+    /// take a frame and check its checksum
+    fn frame(&mut self) -> Option<Record> {
+        let len = usize::try_from(self.u32()?).ok()?;
+        let checksum = self.u32()?;
+        let body = self.take(len)?;
+        if crc32(body) != checksum {
+            return None;
+        }
+        Reader(body).record()
+    }
+
+    /// take the body of a record
+    fn record(&mut self) -> Option<Record> {
+        let tag = *self.take(1)?.first()?;
+        let record = match tag {
+            TAG_SEND => {
+                let count = self.u32()?;
+                let mut messages = Vec::new();
+                for _ in 0..count {
+                    let id = self.u64()?;
+                    let key_count = self.u32()?;
+                    let keys = (0..key_count)
+                        .map(|_| self.string())
+                        .collect::<Option<Vec<String>>>()?;
+                    let payload = self.bytes()?.to_vec();
+                    messages.push(LoggedMessage { id, keys, payload });
+                }
+                Record::Send(messages)
+            }
+            TAG_DELIVER => Record::Deliver(self.u64()?),
+            TAG_RELEASE => Record::Release(self.u64()?, self.string()?),
+            TAG_LAST_ID => Record::LastId(self.u64()?),
+            TAG_DROP => Record::Drop(self.u64()?),
+            _ => return None,
+        };
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod test_wal {
+    use super::{
+        crc32, decode_records, encode_record, replay, LoggedMessage, Record, WriteAheadLog,
+    };
+    use std::{fs, io::Write};
+
+    /// a logged message with `keys`
+    fn logged(id: u64, keys: &[&str]) -> LoggedMessage {
+        LoggedMessage {
+            id,
+            keys: keys.iter().map(|key| String::from(*key)).collect(),
+            payload: id.to_le_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_torn_record_is_cut() {
+        let path =
+            std::env::temp_dir().join(format!("personal_channel_{}_torn.wal", std::process::id()));
+        drop(fs::remove_file(&path));
+        let (mut log, _) = WriteAheadLog::open(&path).unwrap_or_else(|err| panic!("{err}"));
+        assert!(log.append(&Record::Deliver(1)).is_ok());
+        // half a record, as a write failing with a full disk leaves it
+        let torn = encode_record(&Record::Deliver(2)).unwrap_or_default();
+        assert!(log
+            .file
+            .write_all(torn.get(..5).unwrap_or_default())
+            .is_ok());
+        log.cut_torn_record();
+        assert!(log.append(&Record::Deliver(3)).is_ok());
+        let records = decode_records(&fs::read(&path).unwrap_or_default());
+        assert_eq!(records.last(), Some(&Record::Deliver(3)));
+        // a log which can not be cut takes nothing more
+        log.file = fs::File::open(&path).unwrap_or_else(|err| panic!("{err}"));
+        assert!(log.append(&Record::Deliver(4)).is_err());
+        assert!(log.poisoned);
+        assert!(log.append(&Record::Deliver(5)).is_err());
+        assert!(fs::remove_file(&path).is_ok());
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_torn_tail_is_dropped() {
+        let records = vec![
+            Record::Send(vec![logged(1, &["a"]), logged(2, &["b"])]),
+            Record::Deliver(1),
+            Record::Release(1, String::from("a")),
+        ];
+        let mut bytes = Vec::new();
+        for record in &records {
+            bytes.extend(encode_record(record).unwrap_or_default());
+        }
+        assert_eq!(decode_records(&bytes), records);
+        // a crash in the middle of the last write
+        let torn = bytes.len().saturating_sub(3);
+        assert_eq!(
+            decode_records(bytes.get(..torn).unwrap_or_default()).len(),
+            2
+        );
+        // a corrupt byte in the first record
+        if let Some(byte) = bytes.get_mut(10) {
+            *byte ^= 0xFF;
+        }
+        assert!(decode_records(&bytes).is_empty());
+    }
+
+    #[test]
+    fn test_replay() {
+        let recovered = replay(&[
+            Record::Send(vec![logged(2, &["a", "b"]), logged(1, &["b"])]),
+            Record::Send(vec![logged(3, &[])]),
+            Record::Deliver(2),
+            Record::Release(2, String::from("a")),
+            Record::Release(2, String::from("b")),
+            Record::Deliver(1),
+            Record::Deliver(3),
+            Record::Send(vec![logged(4, &["c"])]),
+            Record::Drop(4),
+        ]);
+        // 2 and 3 are completed, 4 is dropped, 1 still holds its key
+        assert_eq!(recovered.messages, vec![logged(1, &["b"])]);
+        assert_eq!(recovered.last_id, 4);
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    fmt::{self, Debug},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// `Weigh` gives the weight of message data for a weighted channel,
+/// usually its size in bytes
+pub trait Weigh {
+    /// the weight of the data, the same data must always weigh the same
+    fn weigh(&self) -> usize;
+}
+
+impl Weigh for Vec<u8> {
+    #[inline]
+    fn weigh(&self) -> usize {
+        self.len()
+    }
+}
+
+impl Weigh for String {
+    #[inline]
+    fn weigh(&self) -> usize {
+        self.len()
+    }
+}
+
+/// `Weigher` bounds a channel by the total weight of its queued messages
+pub(crate) struct Weigher<T> {
+    /// the weight of one message
+    weigh: Box<dyn Fn(&T) -> usize + Send + Sync>,
+    /// the most weight queued at once
+    capacity: usize,
+    /// the weight queued now, changed with `state` locked and
+    /// read without it by `stats`
+    current: AtomicUsize,
+}
+
+impl<T> Weigher<T> {
+    /// create a weigher allowing `capacity` weight
+    pub(crate) fn new<F>(capacity: usize, weigh: F) -> Weigher<T>
+    where
+        F: Fn(&T) -> usize + Send + Sync + 'static,
+    {
+        Weigher {
+            weigh: Box::new(weigh),
+            capacity,
+            current: AtomicUsize::new(0),
+        }
+    }
+
+    /// the total weight of `data`
+    pub(crate) fn weigh<'a, I>(&self, data: I) -> usize
+    where
+        I: IntoIterator<Item = &'a T>,
+        T: 'a,
+    {
+        data.into_iter()
+            .fold(0, |total, data| total.saturating_add((self.weigh)(data)))
+    }
+
+    /// whether `weight` more fits
+    pub(crate) fn fits(&self, weight: usize) -> bool {
+        self.current().saturating_add(weight) <= self.capacity
+    }
+
+    /// whether `weight` is more than the channel ever holds
+    pub(crate) fn exceeds(&self, weight: usize) -> bool {
+        weight > self.capacity
+    }
+
+    /// the weight queued now
+    pub(crate) fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// a message of `data` is queued
+    pub(crate) fn add(&self, data: &T) {
+        let weight = (self.weigh)(data);
+        // only changed with `state` locked, so load and store do not race
+        self.current
+            .store(self.current().saturating_add(weight), Ordering::Relaxed);
+    }
+
+    /// a message of `data` leaves the queue
+    pub(crate) fn sub(&self, data: &T) {
+        let weight = (self.weigh)(data);
+        self.current
+            .store(self.current().saturating_sub(weight), Ordering::Relaxed);
+    }
+}
+
+impl<T> Debug for Weigher<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Weigher")
+            .field("capacity", &self.capacity)
+            .field("current", &self.current())
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod test_weight {
+    use super::Weigher;
+
+    #[test]
+    fn test_weigher() {
+        let weigher = Weigher::new(10, |data: &Vec<u8>| data.len());
+        let data = vec![vec![0_u8; 4], vec![0_u8; 3]];
+        assert_eq!(weigher.weigh(&data), 7);
+        assert!(weigher.fits(10));
+        assert!(weigher.exceeds(11));
+        for one in &data {
+            weigher.add(one);
+        }
+        assert_eq!(weigher.current(), 7);
+        assert!(weigher.fits(3));
+        assert!(!weigher.fits(4));
+        weigher.sub(data.first().unwrap_or(&Vec::new()));
+        assert_eq!(weigher.current(), 3);
+    }
+}
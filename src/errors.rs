@@ -3,6 +3,7 @@ use thiserror::Error;
 #[allow(dead_code)]
 /// this file is used to define errors by ourself.
 #[derive(Error, Debug, PartialEq, Clone, Copy)]
+#[non_exhaustive]
 /// customerized error types
 pub enum Errors {
     /// use thiserror to implement display
@@ -15,6 +16,24 @@ pub enum Errors {
     /// type conversion error
     #[error("Type Conversion Error")]
     TypeConversionError,
+    /// a batch has more messages than the channel can hold
+    #[error("Exceed Channel Capacity")]
+    ExceedCapacity,
+    /// the log of a durable channel can not be written
+    #[error("Write Ahead Log Error")]
+    WriteAheadLogError,
+    /// the connection to a bridge server is broken
+    #[error("Bridge Connection Error")]
+    ConnectionError,
+    /// the channel is closed and takes no more messages
+    #[error("Channel Closed")]
+    Closed,
+    /// the channel is paused and gives out no messages
+    #[error("Channel Paused")]
+    Paused,
+    /// there is no message in the channel
+    #[error("Channel Empty")]
+    Empty,
 }
 /// an alias for Result<T,Errors>, we can simplify our codes
 pub(crate) type Result<T> = std::result::Result<T, Errors>;
@@ -16,7 +16,7 @@
     non_ascii_idents,
     // non_exhaustive_omitted_patterns, unstable
     noop_method_call,
-    pointer_structural_match,
+    // pointer_structural_match, removed: now a hard error
     rust_2021_incompatible_closure_captures,
     rust_2021_incompatible_or_patterns,
     rust_2021_prefixes_incompatible_syntax,
@@ -61,7 +61,7 @@
     clippy::indexing_slicing,
     // clippy::inline_asm_x86_att_syntax, stick to intel syntax
     clippy::inline_asm_x86_intel_syntax,
-    clippy::integer_arithmetic,
+    clippy::arithmetic_side_effects,
     // clippy::integer_division, required in the project
     clippy::let_underscore_must_use,
     clippy::lossy_float_literal,
@@ -88,7 +88,7 @@
     clippy::shadow_unrelated,
     clippy::str_to_string,
     clippy::string_add,
-    clippy::string_to_string,
+    // clippy::string_to_string, removed: covered by clippy::implicit_clone
     clippy::todo,
     clippy::unimplemented,
     clippy::unnecessary_self_imports,
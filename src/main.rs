@@ -16,7 +16,7 @@
     non_ascii_idents,
     // non_exhaustive_omitted_patterns, unstable
     noop_method_call,
-    pointer_structural_match,
+    // pointer_structural_match, removed: now a hard error
     rust_2021_incompatible_closure_captures,
     rust_2021_incompatible_or_patterns,
     rust_2021_prefixes_incompatible_syntax,
@@ -61,7 +61,7 @@
     clippy::indexing_slicing,
     // clippy::inline_asm_x86_att_syntax, stick to intel syntax
     clippy::inline_asm_x86_intel_syntax,
-    clippy::integer_arithmetic,
+    clippy::arithmetic_side_effects,
     // clippy::integer_division, required in the project
     clippy::let_underscore_must_use,
     clippy::lossy_float_literal,
@@ -88,7 +88,7 @@
     clippy::shadow_unrelated,
     clippy::str_to_string,
     clippy::string_add,
-    clippy::string_to_string,
+    // clippy::string_to_string, removed: covered by clippy::implicit_clone
     clippy::todo,
     clippy::unimplemented,
     clippy::unnecessary_self_imports,
@@ -106,9 +106,37 @@
     )]
 //!!
 use personal_channel::util_chan::{Chan, Message};
+use std::{io::Write, sync::Arc, time::Instant};
+
+/// the keys of the messages sent by `graph`, one producer per line
+const WORKLOAD: [&[&[&str]]; 2] = [
+    &[&["tenant-a"], &["tenant-a", "tenant-b"], &["tenant-c"]],
+    &[&["tenant-b"], &["tenant-b", "tenant-d"], &["tenant-a"]],
+];
+
+/// the producer counts `bench` runs with
+const BENCH_PRODUCERS: [usize; 6] = [1, 2, 4, 8, 16, 32];
+/// the messages each producer of `bench` sends
+const BENCH_MESSAGES: usize = 10_000;
+/// the keys the messages of `bench` pick from
+const BENCH_KEYS: usize = 1024;
+/// the shards of the channel `bench` compares with a single shard
+const BENCH_SHARDS: usize = 16;
+
 // mian func just gives a simpile usage for chan.
 // please refer to src/tests for the complete usage
-fn main() {
+// run it with RUST_LOG=debug to see the channel's lifecycle events,
+// run `graph [dot|json]` to dump the blocking graph of a sample workload,
+// or run `bench` in release mode to compare the throughput of a sharded
+// key table with a single shard
+fn main() -> std::io::Result<()> {
+    env_logger::init();
+    let mut args = std::env::args().skip(1);
+    match args.next().as_deref() {
+        Some("graph") => return graph(args.next().as_deref() == Some("json")),
+        Some("bench") => return bench(),
+        _ => {}
+    }
     let (sender, reciever) = Chan::<i32>::create_chan(12);
     let strs = vec![String::from("a"), String::from("b")];
     let message = Message::create_internal_message(strs, 33);
@@ -121,4 +149,112 @@ fn main() {
     } else {
         panic!("error message")
     }
+    Ok(())
+}
+
+/// run a sample workload, hold a few recieved messages so others are
+/// blocked, and print the blocking graph as DOT or JSON
+fn graph(json: bool) -> std::io::Result<()> {
+    let (sender0, reciever) = Chan::<usize>::create_chan(-1);
+    let sender = Arc::new(sender0);
+    let handlers: Vec<_> = WORKLOAD
+        .iter()
+        .map(|messages| {
+            let shared_sender = Arc::clone(&sender);
+            std::thread::spawn(move || {
+                for (i, keys) in messages.iter().enumerate() {
+                    let strs = keys.iter().map(|key| String::from(*key)).collect();
+                    let message = Message::create_internal_message(strs, i);
+                    assert!(shared_sender.send(message).is_ok());
+                }
+            })
+        })
+        .collect();
+    for handler in handlers {
+        assert!(handler.join().is_ok());
+    }
+    // the held messages keep their keys active
+    let held = reciever.recv_batch(2).unwrap_or_default();
+    let snapshot = reciever.snapshot();
+    let rendered = if json {
+        snapshot.to_json()
+    } else {
+        snapshot.to_dot()
+    };
+    writeln!(std::io::stdout(), "{rendered}")?;
+    drop(held);
+    Ok(())
+}
+
+/// send two-key messages from more and more producers, and print the
+/// messages per second of one shard and of `BENCH_SHARDS` shards
+fn bench() -> std::io::Result<()> {
+    let mut stdout = std::io::stdout();
+    writeln!(
+        stdout,
+        "producers  1 shard msg/s  {BENCH_SHARDS} shards msg/s  speedup %"
+    )?;
+    for producers in BENCH_PRODUCERS {
+        let single = throughput(producers, 1);
+        let sharded = throughput(producers, BENCH_SHARDS);
+        let speedup = sharded
+            .saturating_mul(100)
+            .checked_div(single)
+            .unwrap_or_default();
+        writeln!(
+            stdout,
+            "{producers:>9}  {single:>13}  {sharded:>14}  {speedup:>9}"
+        )?;
+    }
+    Ok(())
+}
+
+/// messages per second through a channel with `shards` shards fed by
+/// `producers` threads, the reciever drops every message at once
+fn throughput(producers: usize, shards: usize) -> u128 {
+    let (sender0, reciever) = Chan::<usize>::create_sharded_chan(1024, shards);
+    let sender = Arc::new(sender0);
+    let names: Arc<Vec<String>> =
+        Arc::new((0..BENCH_KEYS).map(|key| format!("key-{key}")).collect());
+    let start = Instant::now();
+    let handlers: Vec<_> = (0..producers)
+        .map(|producer| {
+            let shared_sender = Arc::clone(&sender);
+            let shared_names = Arc::clone(&names);
+            std::thread::spawn(move || {
+                for i in 0..BENCH_MESSAGES {
+                    let first = producer.wrapping_mul(131).wrapping_add(i.wrapping_mul(7));
+                    let keys = [
+                        first,
+                        first
+                            .wrapping_add(i.checked_rem(5).unwrap_or(0))
+                            .wrapping_add(1),
+                    ]
+                    .iter()
+                    .filter_map(|key| shared_names.get(key.checked_rem(BENCH_KEYS)?))
+                    .map(String::from)
+                    .collect();
+                    let message = Message::create_internal_message(keys, i);
+                    assert!(shared_sender.send(message).is_ok());
+                }
+            })
+        })
+        .collect();
+    let total = producers.saturating_mul(BENCH_MESSAGES);
+    let mut delivered = 0_usize;
+    while delivered < total {
+        match reciever.recv_batch(64) {
+            Ok(messages) => delivered = delivered.saturating_add(messages.len()),
+            Err(_) => std::thread::yield_now(),
+        }
+    }
+    for handler in handlers {
+        assert!(handler.join().is_ok());
+    }
+    let nanos = start.elapsed().as_nanos().max(1);
+    u128::try_from(total)
+        .unwrap_or(u128::MAX)
+        .saturating_mul(1_000_000_000)
+        .checked_div(nanos)
+        .unwrap_or_default()
 }
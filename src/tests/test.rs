@@ -1,7 +1,37 @@
 #[cfg(test)]
+#[allow(
+    clippy::unwrap_used, // a failed unwrap fails the test
+    clippy::clone_on_ref_ptr,
+    clippy::semicolon_if_nothing_returned,
+    clippy::shadow_unrelated,
+    clippy::string_add,
+    clippy::uninlined_format_args,
+)]
 pub(crate) mod test_channel {
-    use crate::{channel::mspc_channel::*, errors::Errors};
+    #[cfg(target_os = "linux")]
+    use crate::channel::shm::{ShmReciever, ShmSender, KEY_SIZE};
+    use crate::{
+        channel::{
+            bridge::{BridgeClient, BridgeServer},
+            mspc_channel::*,
+            overflow::OverflowPolicy,
+            select::Select,
+            status::MessageStatus,
+        },
+        errors::Errors,
+    };
     use std::sync::Arc;
+    /// a message with the single key `key`
+    fn keyed(key: &str, data: i32) -> InternalMessage<i32> {
+        InternalMessage::new(vec![String::from(key)], data)
+    }
+
+    /// send a message with `keys` and return its id
+    fn send_keys(sender: &Sender<i32>, keys: &[&str]) -> u64 {
+        let strs = keys.iter().map(|key| String::from(*key)).collect();
+        sender.send(InternalMessage::new(strs, 0)).unwrap()
+    }
+
     #[test]
     fn test_basic_channel() {
         let (sender, reciever) = MspcChannel::<i32>::channel(-1);
@@ -164,4 +194,1027 @@ pub(crate) mod test_channel {
             // messages drop here
         }
     }
+
+    #[test]
+    fn test_message_status() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        let id0 = sender
+            .send(InternalMessage::new(vec![String::from("a")], 0))
+            .unwrap();
+        assert_eq!(sender.status(id0), MessageStatus::Queued);
+        let message0 = reciever.recv().unwrap();
+        assert_eq!(message0.id(), id0);
+        assert_eq!(sender.status(id0), MessageStatus::Active);
+        let id1 = sender
+            .send(InternalMessage::new(vec![String::from("a")], 1))
+            .unwrap();
+        assert_eq!(
+            sender.status(id1),
+            MessageStatus::Blocked(vec![String::from("a")])
+        );
+        drop(message0);
+        assert_eq!(reciever.status(id0), MessageStatus::Completed);
+        assert_eq!(reciever.status(id1), MessageStatus::Queued);
+        let id2 = sender.send(InternalMessage::new(Vec::new(), 2)).unwrap();
+        let message1 = reciever.recv().unwrap();
+        assert_eq!(message1.id(), id2);
+        assert_eq!(sender.status(id2), MessageStatus::Completed);
+        assert_eq!(sender.status(id2.wrapping_add(1)), MessageStatus::Unknown);
+    }
+
+    #[test]
+    fn test_message_ids_are_unique() {
+        let (sender0, _reciever) = MspcChannel::<i32>::channel(-1);
+        let sender = Arc::new(sender0);
+        let mut hanlders = Vec::new();
+        for i in 0..8 {
+            let shared_sender = Arc::clone(&sender);
+            let handler = std::thread::spawn(move || {
+                let mut ids = Vec::new();
+                for j in 0..1000 {
+                    let message = InternalMessage::new(vec![std::format!("{i}-{j}")], j);
+                    ids.push(shared_sender.send(message).unwrap());
+                }
+                ids
+            });
+            hanlders.push(handler);
+        }
+        let mut ids = std::collections::HashSet::new();
+        for handler in hanlders {
+            for id in handler.join().unwrap() {
+                assert_ne!(id, 0);
+                assert!(ids.insert(id));
+            }
+        }
+        assert_eq!(ids.len(), 8000);
+    }
+
+    /// a tiny xorshift generator, so the property tests need no extra crate
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13_u32;
+            self.0 ^= self.0 >> 7_u32;
+            self.0 ^= self.0 << 17_u32;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            usize::try_from(self.next())
+                .unwrap_or(usize::MAX)
+                .checked_rem(bound)
+                .unwrap_or(0)
+        }
+    }
+
+    #[test]
+    fn test_same_key_fifo() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        let id0 = sender
+            .send(InternalMessage::new(vec![String::from("a")], 0))
+            .unwrap();
+        let id1 = sender
+            .send(InternalMessage::new(vec![String::from("b")], 1))
+            .unwrap();
+        // "a" is free, but the earlier message with "a" must go first
+        let id2 = sender
+            .send(InternalMessage::new(
+                vec![String::from("a"), String::from("b")],
+                2,
+            ))
+            .unwrap();
+        let id3 = sender
+            .send(InternalMessage::new(vec![String::from("a")], 3))
+            .unwrap();
+        assert_eq!(
+            sender.status(id2),
+            MessageStatus::Blocked(vec![String::from("a"), String::from("b")])
+        );
+        let message0 = reciever.recv().unwrap();
+        let message1 = reciever.recv().unwrap();
+        let mut ids = vec![message0.id(), message1.id()];
+        ids.sort_unstable();
+        assert_eq!(ids, vec![id0, id1]);
+        assert_eq!(reciever.recv().unwrap_err(), Errors::KeyDuplicate);
+        drop(message0);
+        drop(message1);
+        let message2 = reciever.recv().unwrap();
+        assert_eq!(message2.id(), id2);
+        drop(message2);
+        assert_eq!(reciever.recv().unwrap().id(), id3);
+    }
+
+    #[test]
+    fn test_same_key_fifo_property() {
+        const PRODUCERS: u64 = 4;
+        const MESSAGES: usize = 200;
+        const KEYS: usize = 5;
+        for seed in 1..=20_u64 {
+            let (sender0, reciever) = MspcChannel::<(u64, usize, Vec<usize>)>::channel(-1);
+            let sender = Arc::new(sender0);
+            let mut hanlders = Vec::new();
+            for producer in 0..PRODUCERS {
+                let shared_sender = Arc::clone(&sender);
+                let handler = std::thread::spawn(move || {
+                    let mut rng = XorShift(seed.wrapping_mul(31).wrapping_add(producer));
+                    for seq in 0..MESSAGES {
+                        let mut keys: Vec<usize> =
+                            (0..KEYS).filter(|_| rng.below(3) == 0).collect();
+                        if keys.is_empty() {
+                            keys.push(rng.below(KEYS));
+                        }
+                        let strs = keys.iter().map(|key| std::format!("k{key}")).collect();
+                        let message = InternalMessage::new(strs, (producer, seq, keys));
+                        let _ = shared_sender.send(message).unwrap();
+                        if rng.below(4) == 0 {
+                            std::thread::yield_now();
+                        }
+                    }
+                });
+                hanlders.push(handler);
+            }
+            let mut rng = XorShift(seed);
+            // (key, producer) -> last recieved seq
+            let mut last_seq = std::collections::HashMap::new();
+            let mut held = Vec::new();
+            let mut delivered = 0_usize;
+            while delivered < 4 * MESSAGES {
+                match reciever.recv() {
+                    Ok(message) => {
+                        let (producer, seq, ref keys) = message.data;
+                        for key in keys {
+                            if let Some(last) = last_seq.insert((*key, producer), seq) {
+                                assert!(last < seq, "key k{key} is out of order");
+                            }
+                        }
+                        held.push(message);
+                        delivered = delivered.wrapping_add(1);
+                    }
+                    Err(err) => {
+                        assert_eq!(err, Errors::KeyDuplicate);
+                        // something valid exists whenever no key is active
+                        assert!(!held.is_empty());
+                        held.clear();
+                    }
+                }
+                if rng.below(3) == 0 && !held.is_empty() {
+                    drop(held.swap_remove(rng.below(held.len())));
+                }
+            }
+            for handler in hanlders {
+                handler.join().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_concurrent_send_recv_drop() {
+        send_recv_drop(MspcChannel::channel(16), 2);
+        // one shard, and messages locking many shards at once
+        send_recv_drop(MspcChannel::sharded_channel(16, 1), 4);
+        send_recv_drop(MspcChannel::sharded_channel(16, 4), 4);
+    }
+
+    /// (producer, seq, keys) of a message sent by `send_recv_drop`
+    type Sent = (usize, usize, Vec<usize>);
+
+    /// send messages with up to `keys_per_message` keys from several
+    /// threads, and drop the recieved ones on another thread
+    fn send_recv_drop(
+        (sender0, reciever): (Sender<Sent>, Reciever<Sent>),
+        keys_per_message: usize,
+    ) {
+        const PRODUCERS: usize = 4;
+        const MESSAGES: usize = 500;
+        const KEYS: usize = 6;
+        let sender = Arc::new(sender0);
+        // the keys held by recieved messages not dropped yet
+        let in_use = Arc::new(std::sync::Mutex::new(std::collections::HashSet::new()));
+        let mut producers = Vec::new();
+        for producer in 0..PRODUCERS {
+            let shared_sender = Arc::clone(&sender);
+            producers.push(std::thread::spawn(move || {
+                let mut rng = XorShift(u64::try_from(producer).unwrap().wrapping_add(7));
+                for seq in 0..MESSAGES {
+                    let mut keys = vec![rng.below(KEYS)];
+                    for _ in 1..keys_per_message {
+                        let key = rng.below(KEYS);
+                        if rng.below(2) == 0 && !keys.contains(&key) {
+                            keys.push(key);
+                        }
+                    }
+                    let strs = keys.iter().map(|key| std::format!("k{key}")).collect();
+                    let _ = shared_sender
+                        .send(InternalMessage::new(strs, (producer, seq, keys)))
+                        .unwrap();
+                }
+            }));
+        }
+        // recieved messages are dropped on other threads, racing the sends
+        let (to_dropper, from_reciever) = std::sync::mpsc::channel::<InternalMessage<_>>();
+        let shared_in_use = Arc::clone(&in_use);
+        let dropper = std::thread::spawn(move || {
+            for message in from_reciever {
+                let (_, _, ref keys) = message.data;
+                let mut in_use_guard = shared_in_use.lock().unwrap();
+                for key in keys {
+                    let _ = in_use_guard.remove(key);
+                }
+                drop(in_use_guard);
+                drop(message);
+            }
+        });
+        let shared_sender = Arc::clone(&sender);
+        let observer = std::thread::spawn(move || {
+            for _ in 0..200 {
+                let _ = shared_sender.stats();
+                let _ = shared_sender.snapshot();
+                std::thread::yield_now();
+            }
+        });
+        let mut last_seq = std::collections::HashMap::new();
+        let mut delivered = 0_usize;
+        while delivered < PRODUCERS * MESSAGES {
+            match reciever.recv() {
+                Ok(message) => {
+                    let (producer, seq, ref keys) = message.data;
+                    let mut in_use_guard = in_use.lock().unwrap();
+                    for key in keys {
+                        assert!(in_use_guard.insert(*key), "key k{key} is held twice");
+                        if let Some(last) = last_seq.insert((*key, producer), seq) {
+                            assert!(last < seq, "key k{key} is out of order");
+                        }
+                    }
+                    drop(in_use_guard);
+                    to_dropper.send(message).unwrap();
+                    delivered = delivered.wrapping_add(1);
+                }
+                Err(err) => {
+                    assert_eq!(err, Errors::KeyDuplicate);
+                    std::thread::yield_now();
+                }
+            }
+        }
+        drop(to_dropper);
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        dropper.join().unwrap();
+        observer.join().unwrap();
+        let stats = sender.stats();
+        assert_eq!(stats.queued, 0);
+        assert_eq!(stats.active_keys, 0);
+    }
+
+    #[test]
+    fn test_queue_does_not_grow() {
+        let (sender, reciever) = MspcChannel::<usize>::channel(-1);
+        // a few messages stay blocked behind a recieved one the whole time
+        let held = {
+            let _ = sender
+                .send(InternalMessage::new(vec![String::from("held")], 0))
+                .unwrap();
+            reciever.recv().unwrap()
+        };
+        for data in 1..=4 {
+            let _ = sender
+                .send(InternalMessage::new(vec![String::from("held")], data))
+                .unwrap();
+        }
+        for data in 0..1_000_000 {
+            let strs = vec![std::format!("k{}", data % 8)];
+            let _ = sender.send(InternalMessage::new(strs, data)).unwrap();
+            drop(reciever.recv().unwrap());
+        }
+        // only the blocked messages and the recieved one are kept
+        assert_eq!(
+            reciever.chan().retained(),
+            vec![
+                ("list slots", 5),
+                ("id_to_node", 4),
+                ("id_to_message", 4),
+                ("active_ids", 1),
+                ("enqueue_times", 4),
+                ("waiting", 1),
+                ("active", 1),
+            ]
+        );
+        drop(held);
+        for _ in 1..=4 {
+            drop(reciever.recv().unwrap());
+        }
+        for (map, len) in reciever.chan().retained() {
+            assert_eq!(len, 0, "{map} keeps {len} entries");
+        }
+        assert_eq!(sender.stats().queued, 0);
+    }
+
+    #[test]
+    fn test_channel_is_freed() {
+        let data = Arc::new(0);
+        let (sender, reciever) = MspcChannel::<Arc<i32>>::channel(-1);
+        for key in ["a", "a", "b"] {
+            let message = InternalMessage::new(vec![String::from(key)], Arc::clone(&data));
+            let _ = sender.send(message).unwrap();
+        }
+        // a recieved message may outlive the channel
+        let held = reciever.recv().unwrap();
+        drop(sender);
+        drop(reciever);
+        assert_eq!(Arc::strong_count(&data), 2);
+        drop(held);
+        assert_eq!(Arc::strong_count(&data), 1);
+    }
+
+    #[test]
+    fn test_aged_message_goes_first() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        let id0 = sender
+            .send(InternalMessage::new(vec![String::from("a")], 0))
+            .unwrap();
+        let id1 = sender
+            .send(InternalMessage::new(vec![String::from("b")], 1))
+            .unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        assert!(sender.wait_stats().longest_queued_wait >= std::time::Duration::from_millis(5));
+        // every message is aged now, so the oldest valid one goes first
+        reciever.set_aging_threshold(std::time::Duration::ZERO);
+        assert_eq!(reciever.recv().unwrap().id(), id0);
+        assert_eq!(reciever.recv().unwrap().id(), id1);
+        let stats = reciever.wait_stats();
+        assert_eq!(stats.longest_queued_wait, std::time::Duration::ZERO);
+        assert!(stats.longest_delivered_wait >= std::time::Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_recv_batch() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        let (id0, id1, id2, id3, id4) = (
+            send_keys(&sender, &["a"]),
+            send_keys(&sender, &["b"]),
+            send_keys(&sender, &["a"]),
+            send_keys(&sender, &["c", "b"]),
+            send_keys(&sender, &["d"]),
+        );
+        let ids_of = |batch: &[InternalMessage<i32>]| {
+            let mut ids: Vec<u64> = batch.iter().map(InternalMessage::id).collect();
+            ids.sort_unstable();
+            ids
+        };
+        assert!(reciever.recv_batch(0).unwrap().is_empty());
+        let mut batch = reciever.recv_batch(2).unwrap();
+        assert_eq!(batch.len(), 2);
+        batch.extend(reciever.recv_batch(10).unwrap());
+        // the later "a" and "b" messages wait for the first ones
+        assert_eq!(ids_of(&batch), vec![id0, id1, id4]);
+        assert_eq!(reciever.recv_batch(10).unwrap_err(), Errors::KeyDuplicate);
+        drop(batch);
+        assert_eq!(ids_of(&reciever.recv_batch(10).unwrap()), vec![id2, id3]);
+    }
+
+    #[test]
+    fn test_send_all() {
+        let (sender0, reciever) = MspcChannel::<i32>::channel(3);
+        let sender = Arc::new(sender0);
+        let batch = |data: std::ops::Range<i32>| {
+            data.map(|i| InternalMessage::new(vec![std::format!("{i}")], i))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            sender.send_all(batch(0..4)).unwrap_err(),
+            Errors::ExceedCapacity
+        );
+        assert!(sender.send_all(Vec::new()).unwrap().is_empty());
+        let ids = sender.send_all(batch(0..3)).unwrap();
+        assert_eq!(ids.len(), 3);
+        assert!(ids.windows(2).all(|pair| pair.first() < pair.last()));
+        let shared_sender = Arc::clone(&sender);
+        let handler = std::thread::spawn(move || shared_sender.send_all(batch(3..5)).unwrap());
+        // the whole batch waits until there is room for both messages
+        let first = reciever.recv().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!handler.is_finished());
+        let second = reciever.recv().unwrap();
+        let later_ids = handler.join().unwrap();
+        assert_eq!(later_ids.len(), 2);
+        let rest = reciever.recv_batch(10).unwrap();
+        let mut data: Vec<i32> = rest.iter().map(|message| message.data).collect();
+        data.extend([first.data, second.data]);
+        data.sort_unstable();
+        assert_eq!(data, vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_overflow_policies() {
+        {
+            // a full channel refuses the message
+            let (sender, reciever) =
+                MspcChannel::<i32>::channel_with_policy(1, OverflowPolicy::Reject);
+            let _ = sender.send(keyed("a", 0)).unwrap();
+            assert_eq!(
+                sender.send(keyed("b", 1)).unwrap_err(),
+                Errors::ExceedCapacity
+            );
+            assert_eq!(sender.stats().rejected, 1);
+            assert_eq!(reciever.recv().unwrap().data, 0);
+        }
+        {
+            // the new message is dropped and handed to the drop handler
+            let (sender, reciever) =
+                MspcChannel::<i32>::channel_with_policy(1, OverflowPolicy::DropNewest);
+            let dropped = Arc::new(parking_lot::Mutex::new(Vec::new()));
+            let shared_dropped = Arc::clone(&dropped);
+            reciever.set_drop_handler(move |message| shared_dropped.lock().push(message.data));
+            let _ = sender.send(keyed("a", 0)).unwrap();
+            let id = sender.send(keyed("b", 1)).unwrap();
+            assert_eq!(sender.status(id), MessageStatus::Dropped);
+            assert_eq!(*dropped.lock(), vec![1]);
+            assert_eq!(sender.stats().dropped, 1);
+            assert_eq!(reciever.recv().unwrap().data, 0);
+        }
+        {
+            // the oldest message which can be recieved is dropped, not a blocked one
+            let (sender, reciever) =
+                MspcChannel::<i32>::channel_with_policy(2, OverflowPolicy::DropOldest);
+            let _ = sender.send(keyed("k", 0)).unwrap();
+            let active = reciever.recv().unwrap();
+            let _ = sender.send(keyed("k", 1)).unwrap();
+            let id = sender.send(keyed("m", 2)).unwrap();
+            let _ = sender.send(keyed("n", 3)).unwrap();
+            assert_eq!(sender.status(id), MessageStatus::Dropped);
+            // the dropped message does not release the key of the active one
+            assert_eq!(reciever.recv().unwrap().data, 3);
+            drop(active);
+            assert_eq!(reciever.recv().unwrap().data, 1);
+        }
+        {
+            // the oldest message of the same key is dropped, the next one of
+            // that key can be recieved
+            let (sender, reciever) =
+                MspcChannel::<i32>::channel_with_policy(2, OverflowPolicy::DropOldestSameKey);
+            let _ = sender.send(keyed("a", 0)).unwrap();
+            let _ = sender.send(keyed("a", 1)).unwrap();
+            let _ = sender.send(keyed("a", 2)).unwrap();
+            let first = reciever.recv().unwrap();
+            assert_eq!(first.data, 1);
+            drop(first);
+            assert_eq!(reciever.recv().unwrap().data, 2);
+            assert_eq!(sender.stats().dropped, 1);
+        }
+        {
+            // no queued message shares the key, so the new one is dropped
+            let (sender, reciever) =
+                MspcChannel::<i32>::channel_with_policy(2, OverflowPolicy::DropOldestSameKey);
+            let _ = sender.send(keyed("a", 0)).unwrap();
+            let _ = sender.send(keyed("b", 1)).unwrap();
+            let id = sender.send(keyed("c", 2)).unwrap();
+            assert_eq!(sender.status(id), MessageStatus::Dropped);
+            let mut data: Vec<i32> = reciever
+                .recv_batch(10)
+                .unwrap()
+                .iter()
+                .map(|message| message.data)
+                .collect();
+            data.sort_unstable();
+            assert_eq!(data, vec![0, 1]);
+        }
+    }
+
+    #[test]
+    fn test_key_capacity() {
+        {
+            // a sender waits only for the queue of its own key
+            let (sender0, reciever) = MspcChannel::<i32>::channel(-1);
+            reciever.set_key_capacity(Some(2));
+            let sender = Arc::new(sender0);
+            let _ = sender.send(keyed("a", 0)).unwrap();
+            let _ = sender.send(keyed("a", 1)).unwrap();
+            let shared_sender = Arc::clone(&sender);
+            let handler = std::thread::spawn(move || shared_sender.send(keyed("a", 2)).unwrap());
+            let _ = sender.send(keyed("b", 3)).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            assert!(!handler.is_finished());
+            let mut data: Vec<i32> = reciever
+                .recv_batch(10)
+                .unwrap()
+                .iter()
+                .map(|message| message.data)
+                .collect();
+            data.sort_unstable();
+            assert_eq!(data, vec![0, 3]);
+            let _ = handler.join().unwrap();
+            assert_eq!(sender.stats().queued, 2);
+        }
+        {
+            // a full key queue rejects its senders, other keys still get in
+            let (sender, reciever) =
+                MspcChannel::<i32>::channel_with_policy(10, OverflowPolicy::Reject);
+            reciever.set_key_capacity(Some(1));
+            let _ = sender.send(keyed("a", 0)).unwrap();
+            assert_eq!(
+                sender.send(keyed("a", 1)).unwrap_err(),
+                Errors::ExceedCapacity
+            );
+            let _ = sender.send(keyed("b", 2)).unwrap();
+            assert_eq!(
+                sender
+                    .send_all(vec![keyed("c", 3), keyed("c", 4)])
+                    .unwrap_err(),
+                Errors::ExceedCapacity
+            );
+            assert_eq!(sender.stats().rejected, 3);
+        }
+        {
+            // a full key queue drops its own oldest message
+            let (sender, reciever) =
+                MspcChannel::<i32>::channel_with_policy(10, OverflowPolicy::DropOldest);
+            reciever.set_key_capacity(Some(1));
+            let _ = sender.send(keyed("b", 0)).unwrap();
+            let id = sender.send(keyed("a", 1)).unwrap();
+            let _ = sender.send(keyed("a", 2)).unwrap();
+            assert_eq!(sender.status(id), MessageStatus::Dropped);
+            let mut data: Vec<i32> = reciever
+                .recv_batch(10)
+                .unwrap()
+                .iter()
+                .map(|message| message.data)
+                .collect();
+            data.sort_unstable();
+            assert_eq!(data, vec![0, 2]);
+        }
+    }
+
+    #[test]
+    fn test_weighted_capacity() {
+        let (sender0, reciever) = MspcChannel::<Vec<u8>>::weighted_channel(10, Vec::len);
+        let sender = Arc::new(sender0);
+        let sized = |key: &str, size: usize| {
+            InternalMessage::new(vec![String::from(key)], vec![0_u8; size])
+        };
+        let _ = sender.send(sized("a", 6)).unwrap();
+        let _ = sender.send(sized("a", 4)).unwrap();
+        assert_eq!(sender.stats().weight, 10);
+        // a message heavier than the channel never fits
+        assert_eq!(
+            sender.send(sized("b", 11)).unwrap_err(),
+            Errors::ExceedCapacity
+        );
+        let shared_sender = Arc::clone(&sender);
+        let handler = std::thread::spawn(move || shared_sender.send(sized("b", 3)).unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!handler.is_finished());
+        // the sender waits until enough weight has been recieved
+        assert_eq!(reciever.recv().unwrap().data.len(), 6);
+        let _ = handler.join().unwrap();
+        assert_eq!(sender.stats().weight, 7);
+    }
+
+    #[test]
+    fn test_set_capacity() {
+        let (sender0, reciever) = MspcChannel::<i32>::channel(2);
+        let sender = Arc::new(sender0);
+        let keyed = |i: i32| InternalMessage::new(vec![std::format!("{i}")], i);
+        let _ = sender.send_all(vec![keyed(0), keyed(1)]).unwrap();
+        let shared_sender = Arc::clone(&sender);
+        let handler = std::thread::spawn(move || shared_sender.send(keyed(2)).unwrap());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!handler.is_finished());
+        // a larger capacity lets the blocked sender in
+        reciever.set_capacity(3);
+        let _ = handler.join().unwrap();
+        // an unbounded channel takes everything
+        reciever.set_capacity(-1);
+        assert_eq!(reciever.capacity(), -1);
+        let _ = sender.send_all((3..10).map(keyed)).unwrap();
+        assert_eq!(sender.stats().queued, 10);
+        // a smaller capacity keeps the queued messages, a batch which no
+        // longer fits at all is rejected while it waits
+        reciever.set_capacity(9);
+        let batch_sender = Arc::clone(&sender);
+        let batch_handler =
+            std::thread::spawn(move || batch_sender.send_all(vec![keyed(10), keyed(11)]));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!batch_handler.is_finished());
+        reciever.set_capacity(1);
+        assert_eq!(
+            batch_handler.join().unwrap().unwrap_err(),
+            Errors::ExceedCapacity
+        );
+        assert_eq!(sender.stats().queued, 10);
+        assert_eq!(reciever.recv_batch(10).unwrap().len(), 10);
+        let _ = sender.send(keyed(12)).unwrap();
+    }
+
+    #[test]
+    fn test_close() {
+        let (sender0, reciever) = MspcChannel::<i32>::channel(1);
+        let sender = Arc::new(sender0);
+        let _ = sender.send(keyed("a", 0)).unwrap();
+        let shared_sender = Arc::clone(&sender);
+        let blocked_sender = std::thread::spawn(move || shared_sender.send(keyed("b", 1)));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        // a waiting sender is refused, the queued message is still recieved
+        sender.close();
+        assert!(reciever.is_closed());
+        assert_eq!(blocked_sender.join().unwrap().unwrap_err(), Errors::Closed);
+        assert_eq!(sender.send(keyed("c", 2)).unwrap_err(), Errors::Closed);
+        assert_eq!(reciever.recv().unwrap().data, 0);
+        assert_eq!(reciever.recv().unwrap_err(), Errors::Closed);
+        assert_eq!(sender.stats().rejected, 2);
+    }
+
+    #[test]
+    fn test_close_wakes_reciever() {
+        let (sender, reciever0) = MspcChannel::<i32>::channel(-1);
+        let reciever = Arc::new(reciever0);
+        let shared_reciever = Arc::clone(&reciever);
+        let handler =
+            std::thread::spawn(move || shared_reciever.recv().map(|message| message.data));
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        reciever.close();
+        assert_eq!(handler.join().unwrap().unwrap_err(), Errors::Closed);
+        assert!(sender.is_closed());
+        // a closed and empty channel is ready for `Select`
+        assert_eq!(Select::new().recv(&reciever).ready(), 0);
+    }
+
+    #[test]
+    fn test_close_and_take() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        let _ = sender.send(keyed("a", 0)).unwrap();
+        let _ = sender.send(keyed("a", 1)).unwrap();
+        let active = reciever.recv().unwrap();
+        assert_eq!(active.data, 0);
+        let _ = sender.send(keyed("b", 2)).unwrap();
+        let _ = sender.send(keyed("a", 3)).unwrap();
+        let taken: Vec<i32> = reciever
+            .close_and_take()
+            .iter()
+            .map(|message| message.data)
+            .collect();
+        assert_eq!(taken, vec![1, 2, 3]);
+        let stats = sender.stats();
+        assert_eq!((stats.queued, stats.active_keys), (0, 1));
+        assert_eq!(reciever.recv().unwrap_err(), Errors::Closed);
+        // the recieved message still releases its key
+        let id = active.id();
+        drop(active);
+        assert_eq!(sender.status(id), MessageStatus::Completed);
+        assert_eq!(sender.stats().active_keys, 0);
+        assert!(reciever.close_and_take().is_empty());
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let (sender, reciever0) = MspcChannel::<i32>::channel(-1);
+        let reciever = Arc::new(reciever0);
+        assert_eq!(reciever.try_recv().unwrap_err(), Errors::Empty);
+        let _ = sender.send(keyed("a", 0)).unwrap();
+        let _ = sender.send(keyed("a", 1)).unwrap();
+        let active = reciever.try_recv().unwrap();
+        reciever.pause();
+        // sends go on and the recieved message still releases its key
+        let _ = sender.send(keyed("b", 2)).unwrap();
+        let id = active.id();
+        drop(active);
+        assert_eq!(sender.status(id), MessageStatus::Completed);
+        assert_eq!(reciever.try_recv().unwrap_err(), Errors::Paused);
+        assert_eq!(Select::new().recv(&reciever).try_ready(), None);
+        let shared_reciever = Arc::clone(&reciever);
+        let handler = std::thread::spawn(move || shared_reciever.recv_batch(10).unwrap().len());
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(!handler.is_finished());
+        reciever.resume();
+        assert!(!reciever.is_paused());
+        assert_eq!(handler.join().unwrap(), 2);
+        assert_eq!(reciever.try_recv().unwrap_err(), Errors::Empty);
+    }
+
+    #[test]
+    fn test_select() {
+        let (sender0, reciever0) = MspcChannel::<i32>::channel(-1);
+        let (sender1, reciever1) = MspcChannel::<String>::channel(-1);
+        let select = Select::new().recv(&reciever0).recv(&reciever1);
+        assert_eq!(select.try_ready(), None);
+        let handler0 = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            let message = InternalMessage::new(vec![String::from("a")], String::from("b"));
+            let _ = sender1.send(message).unwrap();
+        });
+        assert_eq!(select.ready(), 1);
+        let message = reciever1.recv().unwrap();
+        handler0.join().unwrap();
+        // a blocked message is not deliverable, its release wakes us up
+        let _ = sender0
+            .send(InternalMessage::new(vec![String::from("a")], 0))
+            .unwrap();
+        let active = reciever0.recv().unwrap();
+        let _ = sender0
+            .send(InternalMessage::new(vec![String::from("a")], 1))
+            .unwrap();
+        assert_eq!(select.try_ready(), None);
+        let handler1 = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            drop(active);
+        });
+        assert_eq!(select.ready(), 0);
+        assert_eq!(reciever0.recv().unwrap().data, 1);
+        handler1.join().unwrap();
+        drop(message);
+    }
+
+    #[test]
+    fn test_stats() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(2);
+        for i in 0..2 {
+            let _ = sender
+                .send(InternalMessage::new(vec![String::from("a")], i))
+                .unwrap();
+        }
+        let batch = (0..3).map(|i| InternalMessage::new(Vec::new(), i));
+        assert!(sender.send_all(batch).is_err());
+        let stats0 = sender.stats();
+        assert_eq!((stats0.queued, stats0.eligible, stats0.blocked), (2, 1, 1));
+        assert_eq!((stats0.sent, stats0.recieved, stats0.rejected), (2, 0, 3));
+        assert_eq!(stats0.active_keys, 0);
+        let message = reciever.recv().unwrap();
+        let stats1 = reciever.stats();
+        assert_eq!((stats1.queued, stats1.eligible, stats1.blocked), (1, 0, 1));
+        assert_eq!((stats1.recieved, stats1.active_keys), (1, 1));
+        assert_eq!(stats1.delivery_latency.count, 1);
+        assert_eq!(stats1.hold_time.count, 0);
+        drop(message);
+        let stats2 = reciever.stats();
+        assert_eq!((stats2.eligible, stats2.active_keys), (1, 0));
+        assert_eq!(stats2.hold_time.count, 1);
+        assert_eq!(
+            stats2.wait.longest_delivered_wait,
+            stats2.delivery_latency.max
+        );
+    }
+
+    #[test]
+    fn test_hot_keys() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        let _ = send_keys(&sender, &["a"]);
+        let active = reciever.recv().unwrap();
+        for _ in 0..3 {
+            let _ = send_keys(&sender, &["a", "b"]);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        drop(active);
+        let hot_keys = reciever.hot_keys(10);
+        let keys: Vec<(&str, u64)> = hot_keys
+            .iter()
+            .map(|hot| (hot.key.as_str(), hot.blocked_count))
+            .collect();
+        assert_eq!(keys, vec![("a", 3), ("b", 2)]);
+        let hot = sender.hot_keys(1);
+        assert!(hot.first().unwrap().blocked_time >= std::time::Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let (sender, reciever) = MspcChannel::<i32>::channel(-1);
+        let id0 = send_keys(&sender, &["a"]);
+        let active = reciever.recv().unwrap();
+        let id1 = send_keys(&sender, &["a", "b"]);
+        let id2 = send_keys(&sender, &["b"]);
+        let id3 = send_keys(&sender, &["c"]);
+        let snapshot = reciever.snapshot();
+        let queued: Vec<(u64, bool, Vec<String>, Vec<u64>)> = snapshot
+            .queued
+            .into_iter()
+            .map(|message| {
+                (
+                    message.id,
+                    message.eligible,
+                    message.blocked_by_keys,
+                    message.waiting_behind,
+                )
+            })
+            .collect();
+        assert_eq!(
+            queued,
+            vec![
+                (id3, true, Vec::new(), Vec::new()),
+                (id1, false, vec![String::from("a")], Vec::new()),
+                (id2, false, Vec::new(), vec![id1]),
+            ]
+        );
+        let active_keys: Vec<(String, u64)> = snapshot
+            .active_keys
+            .into_iter()
+            .map(|key| (key.key, key.holder))
+            .collect();
+        assert_eq!(active_keys, vec![(String::from("a"), id0)]);
+        drop(active);
+        assert!(sender.snapshot().active_keys.is_empty());
+    }
+
+    #[test]
+    fn test_durable_recovery() {
+        let path = std::env::temp_dir().join(format!(
+            "personal_channel_{}_recovery.wal",
+            std::process::id()
+        ));
+        // a log left by an earlier run of the test
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        let strs = |keys: &[&str]| keys.iter().map(|key| String::from(*key)).collect();
+        let (sender, reciever) = MspcChannel::<String>::durable_channel(&path, -1).unwrap();
+        let id0 = sender
+            .send(InternalMessage::new(strs(&["a"]), String::from("done")))
+            .unwrap();
+        let [id1, id2] = *sender
+            .send_all(vec![
+                InternalMessage::new(strs(&["a", "b"]), String::from("active")),
+                InternalMessage::new(strs(&["b"]), String::from("queued")),
+            ])
+            .unwrap()
+        else {
+            panic!("two messages are sent")
+        };
+        let done = reciever.recv().unwrap();
+        assert_eq!(done.id(), id0);
+        drop(done);
+        // the process crashes while it holds the second message
+        let active = reciever.recv().unwrap();
+        sender.sync().unwrap();
+
+        let (sender2, reciever2) = MspcChannel::<String>::durable_channel(&path, -1).unwrap();
+        let redelivered = reciever2.recv().unwrap();
+        assert_eq!(
+            (redelivered.id(), redelivered.data.as_str()),
+            (id1, "active")
+        );
+        drop(redelivered);
+        let queued = reciever2.recv().unwrap();
+        assert_eq!((queued.id(), queued.data.as_str()), (id2, "queued"));
+        drop(queued);
+        // ids go on after the recovered ones
+        let id3 = sender2
+            .send(InternalMessage::new(strs(&["c"]), String::new()))
+            .unwrap();
+        assert!(id3 > id2);
+        drop(reciever2.recv().unwrap());
+        drop((active, sender, reciever));
+
+        // everything is completed now
+        let (sender3, _reciever3) = MspcChannel::<String>::durable_channel(&path, -1).unwrap();
+        assert_eq!(sender3.stats().queued, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_tcp_bridge_backpressure() {
+        let (sender, reciever) = MspcChannel::<String>::channel(1);
+        let server = BridgeServer::bind_tcp("127.0.0.1:0", sender).unwrap();
+        let addr = server.local_addr().unwrap();
+        let _server = std::thread::spawn(move || server.serve());
+        let client = Arc::new(BridgeClient::<String>::connect_tcp(addr).unwrap());
+        let keys = vec![String::from("a")];
+        let id0 = client
+            .send(InternalMessage::new(keys.clone(), String::from("first")))
+            .unwrap();
+        // the channel is full, the second send waits for the reciever
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        let shared_client = Arc::clone(&client);
+        let handler = std::thread::spawn(move || {
+            let res = shared_client.send(InternalMessage::new(keys, String::from("second")));
+            done_tx.send(()).unwrap();
+            res
+        });
+        assert!(done_rx
+            .recv_timeout(std::time::Duration::from_millis(100))
+            .is_err());
+        let first = reciever.recv().unwrap();
+        assert_eq!((first.id(), first.data.as_str()), (id0, "first"));
+        drop(first);
+        let id1 = handler.join().unwrap().unwrap();
+        assert_eq!(reciever.recv().unwrap().id(), id1);
+        // the server refuses data it can not decode
+        let bytes_client = BridgeClient::<Vec<u8>>::connect_tcp(addr).unwrap();
+        let invalid = InternalMessage::new(Vec::new(), vec![0xFF_u8]);
+        assert_eq!(bytes_client.send(invalid), Err(Errors::MessageContentError));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unix_bridge() {
+        let path = std::env::temp_dir().join(format!(
+            "personal_channel_{}_bridge.sock",
+            std::process::id()
+        ));
+        if path.exists() {
+            std::fs::remove_file(&path).unwrap();
+        }
+        let (sender, reciever) = MspcChannel::<u64>::channel(-1);
+        let server = BridgeServer::bind_unix(&path, sender).unwrap();
+        assert!(server.local_addr().is_none());
+        let _server = std::thread::spawn(move || server.serve());
+        let client = BridgeClient::<u64>::connect_unix(&path).unwrap();
+        let strs = vec![String::from("a"), String::from("b")];
+        let id = client.send(InternalMessage::new(strs, 42)).unwrap();
+        let message = reciever.recv().unwrap();
+        assert_eq!((message.id(), message.data), (id, 42));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// the env var telling `shm_producer` where the ring is
+    #[cfg(target_os = "linux")]
+    const SHM_RING_ENV: &str = "PERSONAL_CHANNEL_SHM_RING";
+    /// the keys sent by `shm_producer`, in a cycle
+    #[cfg(target_os = "linux")]
+    const SHM_KEYS: [&[&str]; 3] = [&["a"], &["a", "b"], &["b"]];
+
+    /// the producer process of `test_shm_across_processes`
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "run by test_shm_across_processes in a child process"]
+    fn shm_producer() {
+        let path = std::env::var(SHM_RING_ENV).unwrap();
+        let sender = ShmSender::<u64>::open(path).unwrap();
+        for (i, keys) in (0..100_u64).zip(SHM_KEYS.iter().cycle()) {
+            let strs = keys.iter().map(|key| String::from(*key)).collect();
+            sender.send(InternalMessage::new(strs, i)).unwrap();
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_shm_across_processes() {
+        let dir = std::path::Path::new("/dev/shm");
+        let dir = if dir.is_dir() {
+            dir.to_path_buf()
+        } else {
+            std::env::temp_dir()
+        };
+        let path = dir.join(format!("personal_channel_{}_shm", std::process::id()));
+        // a small ring, so the producer waits for the consumer
+        let reciever = ShmReciever::<u64>::create(&path, 4, 64).unwrap();
+        let mut child = std::process::Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "tests::test::test_channel::shm_producer",
+                "--ignored",
+                "--quiet",
+            ])
+            .env(SHM_RING_ENV, &path)
+            .stdout(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+        let mut last_of_key = std::collections::HashMap::new();
+        for _ in 0..100 {
+            let message = reciever.recv().unwrap();
+            // there is one producer, so every key keeps its send order
+            for key in message.to_detached().keys {
+                let last = last_of_key.insert(key, message.data);
+                assert!(last < Some(message.data));
+            }
+        }
+        assert!(child.wait().unwrap().success());
+        assert_eq!(last_of_key.len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_shm_key_table_is_shared() {
+        let path =
+            std::env::temp_dir().join(format!("personal_channel_{}_keys", std::process::id()));
+        let reciever = ShmReciever::<u64>::create(&path, 4, 256).unwrap();
+        let sender = ShmSender::<u64>::open(&path).unwrap();
+        let message = |keys: &[&str], data: u64| {
+            InternalMessage::new(keys.iter().map(|key| String::from(*key)).collect(), data)
+        };
+        sender.send(message(&["a", "b"], 0)).unwrap();
+        sender.send(message(&["a"], 1)).unwrap();
+        sender.send(message(&["c"], 2)).unwrap();
+        let first = reciever.recv().unwrap();
+        // the producer side reads the holders from the mapping
+        assert!(sender.is_key_active("a") && sender.is_key_active("b"));
+        assert!(!sender.is_key_active("c"));
+        // the second message waits for "a", so the third goes first
+        assert_eq!(reciever.recv().unwrap().data, 2);
+        assert_eq!(reciever.recv().unwrap_err(), Errors::KeyDuplicate);
+        drop(first);
+        assert!(!sender.is_key_active("a") && !sender.is_key_active("b"));
+        assert_eq!(reciever.recv().unwrap().data, 1);
+        let long_key = "k".repeat(KEY_SIZE + 1);
+        assert_eq!(
+            sender.send(message(&[long_key.as_str()], 3)),
+            Err(Errors::ExceedCapacity)
+        );
+        // 4 slots hold 16 keys, more would never fit in the key table
+        let keys: Vec<String> = (0..=16).map(|key| format!("k{key}")).collect();
+        let mut names: Vec<&str> = keys.iter().map(String::as_str).collect();
+        assert_eq!(sender.send(message(&names, 4)), Err(Errors::ExceedCapacity));
+        names.truncate(16);
+        sender.send(message(&names, 5)).unwrap();
+        assert_eq!(reciever.recv().unwrap().data, 5);
+        std::fs::remove_file(&path).unwrap();
+    }
 }
@@ -1,6 +1,21 @@
-use std::marker::PhantomData;
+use std::{io, marker::PhantomData, path::Path};
 
 use crate::channel::mspc_channel::{InternalMessage, MspcChannel, Reciever, Sender};
+#[cfg(target_os = "linux")]
+pub use crate::channel::shm::{ShmReciever, ShmSender};
+pub use crate::channel::{
+    bridge::{BridgeClient, BridgeServer},
+    contention::HotKey,
+    detached::{DetachedMessage, MessageMetadata},
+    metrics::{ChannelStats, HistogramSnapshot, WaitStats},
+    overflow::OverflowPolicy,
+    select::Select,
+    snapshot::{ActiveKey, ChannelSnapshot, QueuedMessage},
+    status::MessageStatus,
+    wal::Persist,
+    weight::Weigh,
+};
+pub use crate::errors::Errors;
 /// Chan is a wrapper for `mspc_channel`
 #[derive(Debug)]
 pub struct Chan<T> {
@@ -23,6 +38,66 @@ impl<T> Chan<T> {
     pub fn create_chan(bounded_size: i32) -> (Sender<T>, Reciever<T>) {
         MspcChannel::<T>::channel(bounded_size)
     }
+
+    /// `create_sharded_chan` func is like `create_chan`, but splits the
+    /// state of the keys into `shards` shards by key hash. `create_chan`
+    /// uses 16 shards, and one shard keeps every key behind the same lock
+    #[inline]
+    #[must_use]
+    pub fn create_sharded_chan(bounded_size: i32, shards: usize) -> (Sender<T>, Reciever<T>) {
+        MspcChannel::<T>::sharded_channel(bounded_size, shards)
+    }
+
+    /// `create_chan_with_policy` func is used to get sender and reciever of
+    /// a bounded channel which handles a full bound with `policy`
+    #[inline]
+    #[must_use]
+    pub fn create_chan_with_policy(
+        bounded_size: i32,
+        policy: OverflowPolicy,
+    ) -> (Sender<T>, Reciever<T>) {
+        MspcChannel::<T>::channel_with_policy(bounded_size, policy)
+    }
+
+    /// `create_weighted_chan` func is used to get sender and reciever of a
+    /// channel holding at most `max_weight` total `Weigh::weigh` of queued
+    /// messages, a sender waits until enough weight has been recieved
+    #[inline]
+    #[must_use]
+    pub fn create_weighted_chan(max_weight: usize) -> (Sender<T>, Reciever<T>)
+    where
+        T: Weigh + 'static,
+    {
+        MspcChannel::<T>::weighted_channel(max_weight, T::weigh)
+    }
+
+    /// `create_weighted_chan_by` func is like `create_weighted_chan`, but
+    /// the weight of a message is given by `weigh`
+    #[inline]
+    #[must_use]
+    pub fn create_weighted_chan_by<F>(max_weight: usize, weigh: F) -> (Sender<T>, Reciever<T>)
+    where
+        F: Fn(&T) -> usize + Send + Sync + 'static,
+    {
+        MspcChannel::<T>::weighted_channel(max_weight, weigh)
+    }
+
+    /// `create_durable_chan` func is used to get a sender and reciever whose
+    /// messages are logged to the file at `path` and recovered after a crash
+    ///
+    /// # Errors
+    ///
+    /// it fails when the log can not be read, rewritten or decoded
+    #[inline]
+    pub fn create_durable_chan<P: AsRef<Path>>(
+        path: P,
+        bounded_size: i32,
+    ) -> io::Result<(Sender<T>, Reciever<T>)>
+    where
+        T: Persist,
+    {
+        MspcChannel::<T>::durable_channel(path.as_ref(), bounded_size)
+    }
 }
 
 impl<T> Message<T> {